@@ -0,0 +1,486 @@
+use std::{
+    collections::BTreeSet,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use syn::Meta;
+use toml::{Table, Value};
+
+use crate::{
+    features::{enable_features, requested_features, EnabledFeatures},
+    manifest::{edition, find_manifest, read_manifest},
+    with_path, CfgSet, ExpandError, Result,
+};
+
+/// The library crates bundled into the expanded crate, each as a top-level module.
+pub(crate) struct Bundle {
+    /// The crates, ordered so that each crate comes after its dependencies.
+    pub crates: Vec<Crate>,
+    /// The crates that the expanded crate depends on, as pairs of their names in code and indices in `crates`.
+    pub deps: Vec<(String, usize)>,
+    /// The `[target.'...'.dependencies]` tables left out because their platforms cannot be decided,
+    /// as pairs of the packages and the target keys.
+    pub undecided_targets: Vec<(String, String)>,
+}
+
+pub(crate) struct Crate {
+    /// The name of the module that the crate becomes.
+    pub module: String,
+    package: String,
+    /// The version or the path of the package, used in error messages.
+    source: String,
+    /// The root file of the library.
+    pub lib: PathBuf,
+    pub edition: String,
+    /// The enabled features.
+    pub features: Vec<String>,
+    /// The crates that the crate depends on, as pairs of their names in code and indices in `crates`.
+    pub deps: Vec<(String, usize)>,
+    manifest_path: PathBuf,
+    manifest: Table,
+    /// The index of the package in `Cargo.lock`.
+    locked: Option<usize>,
+    /// The features requested by the dependents.
+    requested: BTreeSet<String>,
+}
+
+/// A `[[package]]` entry of `Cargo.lock`.
+struct LockedPackage {
+    name: String,
+    version: String,
+    source: Option<String>,
+    /// Dependencies in the form of `name` or `name version`.
+    dependencies: Vec<String>,
+}
+
+struct Resolver {
+    packages: Vec<LockedPackage>,
+    vendor_dir: Option<PathBuf>,
+    /// The configuration used to evaluate the platforms of `[target.'cfg(...)'.dependencies]`.
+    cfg: CfgSet,
+    crates: Vec<Crate>,
+    undecided_targets: Vec<(String, String)>,
+}
+
+/// Resolves the dependencies `names` of the package containing the crate whose root file is `path`,
+/// together with their own dependencies.
+///
+/// The exact versions of registry dependencies are taken from `Cargo.lock`, and their sources are looked up
+/// in `vendor_dir` or in the registry cache of Cargo, without accessing the network.
+/// A package required by several crates is bundled once, and requiring two different versions of a package is an error.
+/// The features of each crate are the union of those requested by its dependents, including `root`,
+/// the features of the package itself.
+pub(crate) fn resolve(
+    path: &Path,
+    names: &[String],
+    vendor_dir: Option<&Path>,
+    cfg: Option<&CfgSet>,
+    root: &EnabledFeatures,
+) -> Result<Bundle> {
+    let manifest_path = find_manifest(path).ok_or_else(|| {
+        anyhow!(
+            "`Cargo.toml` for `{}` is not found, which is required to bundle dependencies",
+            path.display()
+        )
+    })?;
+    let manifest = read_manifest(&manifest_path)?;
+    let mut resolver = Resolver {
+        packages: read_lockfile(&manifest_path)?,
+        vendor_dir: vendor_dir.map(Path::to_path_buf),
+        cfg: cfg.cloned().unwrap_or_default(),
+        crates: Vec::new(),
+        undecided_targets: Vec::new(),
+    };
+    let package_name = manifest
+        .get("package")
+        .and_then(|p| p.get("name"))
+        .and_then(|n| n.as_str());
+    let locked = resolver
+        .packages
+        .iter()
+        .position(|p| Some(p.name.as_str()) == package_name && p.source.is_none());
+    let entries = dependency_entries(&manifest);
+    let mut deps = Vec::new();
+    for name in names {
+        let code_name = name.replace('-', "_");
+        let Some((key, value)) = entries
+            .iter()
+            .find(|(key, _)| key.replace('-', "_") == code_name)
+        else {
+            return Err(anyhow!(
+                "`{name}` is not a dependency in `{}`",
+                manifest_path.display()
+            )
+            .into());
+        };
+        let index = resolver.add_crate(&manifest_path, locked, key, value)?;
+        let mut features = requested_features(value);
+        features.extend(dep_features(root, key));
+        resolver.crates[index].requested.extend(features);
+        deps.push((code_name, index));
+    }
+    resolver.resolve_features()?;
+    Ok(resolver.finish(deps))
+}
+
+/// Returns the features of the dependency `key` enabled by `enabled`.
+fn dep_features<'a>(
+    enabled: &'a EnabledFeatures,
+    key: &'a str,
+) -> impl Iterator<Item = String> + 'a {
+    enabled
+        .dep_features
+        .iter()
+        .filter(move |(dep, _)| dep == key)
+        .map(|(_, feature)| feature.clone())
+}
+
+impl Resolver {
+    /// Adds the dependency `key = value` in the manifest at `manifest_path` if it is not added yet,
+    /// and returns its index in `crates`.
+    fn add_crate(
+        &mut self,
+        manifest_path: &Path,
+        parent: Option<usize>,
+        key: &str,
+        value: &Value,
+    ) -> Result<usize> {
+        let package = value
+            .get("package")
+            .and_then(|p| p.as_str())
+            .unwrap_or(key)
+            .to_string();
+        let path = value.get("path").and_then(|p| p.as_str());
+        if path.is_none() && value.get("git").is_some() {
+            return Err(anyhow!("git dependency `{package}` cannot be bundled").into());
+        }
+        let (dir, version, source) = match path {
+            Some(path) => {
+                let dir = manifest_path.parent().unwrap().join(path);
+                let dir = dir.canonicalize().unwrap_or(dir);
+                let source = format!("{package} at `{}`", dir.display());
+                (Some(dir), None, source)
+            }
+            None => {
+                let version = self.locked_version(parent, &package)?;
+                let source = format!("{package} {version}");
+                (None, Some(version), source)
+            }
+        };
+        if let Some(i) = self.crates.iter().position(|c| c.package == package) {
+            if self.crates[i].source != source {
+                return Err(anyhow!(
+                    "`{package}` cannot be bundled because both `{}` and `{source}` are required",
+                    self.crates[i].source
+                )
+                .into());
+            }
+            return Ok(i);
+        }
+        let dir = match (dir, &version) {
+            (Some(dir), _) => dir,
+            (None, Some(version)) => self.registry_source(&package, version)?,
+            (None, None) => unreachable!(),
+        };
+        let dep_manifest_path = dir.join("Cargo.toml");
+        let manifest = read_manifest(&dep_manifest_path)?;
+        check_bundleable(&package, &dir, &manifest)?;
+        let lib = manifest
+            .get("lib")
+            .and_then(|l| l.get("path"))
+            .and_then(|p| p.as_str())
+            .unwrap_or("src/lib.rs");
+        let locked = self.packages.iter().position(|p| {
+            p.name == package
+                && match &version {
+                    Some(v) => &p.version == v && p.source.is_some(),
+                    None => p.source.is_none(),
+                }
+        });
+        self.crates.push(Crate {
+            module: package.replace('-', "_"),
+            package,
+            source,
+            lib: dir.join(lib),
+            edition: edition(&manifest, &dep_manifest_path)?.unwrap_or_else(|| "2021".to_string()),
+            features: Vec::new(),
+            deps: Vec::new(),
+            manifest_path: dep_manifest_path,
+            manifest,
+            locked,
+            requested: BTreeSet::new(),
+        });
+        Ok(self.crates.len() - 1)
+    }
+
+    /// Enables the features of the crates and the optional dependencies they enable, until nothing changes.
+    fn resolve_features(&mut self) -> Result<()> {
+        loop {
+            let mut changed = false;
+            let mut i = 0;
+            while i < self.crates.len() {
+                let c = &self.crates[i];
+                let enabled = enable_features(&c.manifest, c.requested.iter().cloned())?;
+                let manifest_path = c.manifest_path.clone();
+                let locked = c.locked;
+                for (key, value) in self.platform_dependency_entries(i) {
+                    let optional = value.get("optional").and_then(|o| o.as_bool()) == Some(true);
+                    if optional && !enabled.deps.contains(&key) {
+                        continue;
+                    }
+                    let index = self.add_crate(&manifest_path, locked, &key, &value)?;
+                    let dep = (key.replace('-', "_"), index);
+                    if !self.crates[i].deps.contains(&dep) {
+                        self.crates[i].deps.push(dep);
+                        changed = true;
+                    }
+                    for feature in requested_features(&value)
+                        .into_iter()
+                        .chain(dep_features(&enabled, &key))
+                    {
+                        changed |= self.crates[index].requested.insert(feature);
+                    }
+                }
+                self.crates[i].features = enabled.features.into_iter().collect();
+                i += 1;
+            }
+            if !changed {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns the dependency entries of the crate `i` for the configured platform.
+    ///
+    /// `[target.'...'.dependencies]` tables whose platforms cannot be decided are left out and recorded.
+    fn platform_dependency_entries(&mut self, i: usize) -> Vec<(String, Value)> {
+        let c = &self.crates[i];
+        let mut entries = Vec::new();
+        for (target, key, value) in dependency_entries_by_target(&c.manifest) {
+            if let Some(target) = target {
+                let pred = target
+                    .strip_prefix("cfg(")
+                    .and_then(|t| t.strip_suffix(')'))
+                    .and_then(|t| syn::parse_str::<Meta>(t).ok());
+                match pred.and_then(|pred| self.cfg.eval(&pred)) {
+                    Some(true) => {}
+                    Some(false) => continue,
+                    None => {
+                        let undecided = (c.package.clone(), target.to_string());
+                        if !self.undecided_targets.contains(&undecided) {
+                            self.undecided_targets.push(undecided);
+                        }
+                        continue;
+                    }
+                }
+            }
+            if !entries.iter().any(|(k, _)| k == key) {
+                entries.push((key.clone(), value.clone()));
+            }
+        }
+        entries
+    }
+
+    /// Returns the bundle with the crates ordered so that each crate comes after its dependencies.
+    fn finish(self, deps: Vec<(String, usize)>) -> Bundle {
+        fn visit(crates: &[Crate], i: usize, visited: &mut Vec<bool>, order: &mut Vec<usize>) {
+            if visited[i] {
+                return;
+            }
+            visited[i] = true;
+            for (_, dep) in &crates[i].deps {
+                visit(crates, *dep, visited, order);
+            }
+            order.push(i);
+        }
+        let mut visited = vec![false; self.crates.len()];
+        let mut order = Vec::new();
+        for i in 0..self.crates.len() {
+            visit(&self.crates, i, &mut visited, &mut order);
+        }
+        let mut new_index = vec![0; self.crates.len()];
+        for (new, old) in order.iter().enumerate() {
+            new_index[*old] = new;
+        }
+        let remap = |deps: Vec<(String, usize)>| -> Vec<(String, usize)> {
+            deps.into_iter()
+                .map(|(name, i)| (name, new_index[i]))
+                .collect()
+        };
+        let mut crates: Vec<Option<Crate>> = self.crates.into_iter().map(Some).collect();
+        let crates = order
+            .iter()
+            .map(|i| {
+                let mut c = crates[*i].take().unwrap();
+                c.deps = remap(std::mem::take(&mut c.deps));
+                c
+            })
+            .collect();
+        Bundle {
+            crates,
+            deps: remap(deps),
+            undecided_targets: self.undecided_targets,
+        }
+    }
+
+    /// Returns the version of `package` that `parent` depends on according to `Cargo.lock`.
+    fn locked_version(&self, parent: Option<usize>, package: &str) -> Result<String> {
+        let candidates: Vec<&LockedPackage> = self
+            .packages
+            .iter()
+            .filter(|p| p.name == package && p.source.is_some())
+            .collect();
+        if let [p] = &candidates[..] {
+            return Ok(p.version.clone());
+        }
+        if let Some(parent) = parent {
+            for d in &self.packages[parent].dependencies {
+                let mut words = d.split_whitespace();
+                if words.next() == Some(package) {
+                    if let Some(version) = words.next() {
+                        return Ok(version.to_string());
+                    }
+                }
+            }
+        }
+        Err(anyhow!("the version of `{package}` could not be determined from `Cargo.lock`").into())
+    }
+
+    /// Returns the directory containing the source of `package` of `version`.
+    fn registry_source(&self, package: &str, version: &str) -> Result<PathBuf> {
+        let dir_name = format!("{package}-{version}");
+        if let Some(vendor_dir) = &self.vendor_dir {
+            for dir in [vendor_dir.join(&dir_name), vendor_dir.join(package)] {
+                if dir.join("Cargo.toml").is_file() {
+                    return Ok(dir);
+                }
+            }
+            return Err(anyhow!(
+                "the source of `{package} {version}` is not found in `{}`",
+                vendor_dir.display()
+            )
+            .into());
+        }
+        let cargo_home = match env::var_os("CARGO_HOME") {
+            Some(home) => PathBuf::from(home),
+            None => env::home_dir()
+                .ok_or_else(|| anyhow!("the home directory is not found"))?
+                .join(".cargo"),
+        };
+        let src = cargo_home.join("registry").join("src");
+        if let Ok(entries) = fs::read_dir(&src) {
+            for entry in entries.flatten() {
+                let dir = entry.path().join(&dir_name);
+                if dir.join("Cargo.toml").is_file() {
+                    return Ok(dir);
+                }
+            }
+        }
+        Err(ExpandError::new(
+            None,
+            anyhow!(
+                "the source of `{package} {version}` is not found in `{}`",
+                src.display()
+            ),
+        )
+        .with_help("run `cargo fetch` to download it, or specify `--vendor-dir`"))
+    }
+}
+
+/// Returns an error if the package cannot be bundled as plain source.
+fn check_bundleable(package: &str, dir: &Path, manifest: &Table) -> Result<()> {
+    let build = manifest.get("package").and_then(|p| p.get("build"));
+    let has_build_script = match build {
+        Some(Value::Boolean(b)) => *b,
+        Some(_) => true,
+        None => dir.join("build.rs").is_file(),
+    };
+    if has_build_script {
+        return Err(anyhow!("`{package}` cannot be bundled because it has a build script").into());
+    }
+    let lib = manifest.get("lib");
+    let proc_macro = ["proc-macro", "proc_macro"]
+        .iter()
+        .any(|k| lib.and_then(|l| l.get(k)).and_then(|v| v.as_bool()) == Some(true));
+    if proc_macro {
+        return Err(
+            anyhow!("`{package}` cannot be bundled because it is a proc-macro crate").into(),
+        );
+    }
+    Ok(())
+}
+
+/// Returns the entries of `[dependencies]` and `[target.'cfg(...)'.dependencies]` for all platforms.
+pub(crate) fn dependency_entries(manifest: &Table) -> Vec<(String, Value)> {
+    let mut entries: Vec<(String, Value)> = Vec::new();
+    for (_, key, value) in dependency_entries_by_target(manifest) {
+        if !entries.iter().any(|(k, _)| k == key) {
+            entries.push((key.clone(), value.clone()));
+        }
+    }
+    entries
+}
+
+/// Returns the dependency entries with the keys of their `[target.'...']` tables, or `None` for `[dependencies]`.
+fn dependency_entries_by_target(manifest: &Table) -> Vec<(Option<&str>, &String, &Value)> {
+    let mut tables = vec![(None, manifest.get("dependencies"))];
+    if let Some(targets) = manifest.get("target").and_then(|t| t.as_table()) {
+        tables.extend(
+            targets
+                .iter()
+                .map(|(target, t)| (Some(target.as_str()), t.get("dependencies"))),
+        );
+    }
+    let mut entries = Vec::new();
+    for (target, table) in tables {
+        for (key, value) in table.and_then(|t| t.as_table()).into_iter().flatten() {
+            entries.push((target, key, value));
+        }
+    }
+    entries
+}
+
+/// Reads the `Cargo.lock` next to `manifest_path` or in the nearest ancestor directory (the workspace root).
+fn read_lockfile(manifest_path: &Path) -> Result<Vec<LockedPackage>> {
+    let Some(path) = manifest_path
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|p| p.is_file())
+    else {
+        return Err(ExpandError::new(None, anyhow!("`Cargo.lock` is not found"))
+            .with_help("run `cargo generate-lockfile` to create it"));
+    };
+    let text = with_path(fs::read_to_string(&path), &path)?;
+    let lock: Table = text
+        .parse()
+        .map_err(|e| anyhow!("Could not parse `{}` ({e})", path.display()))?;
+    let mut packages = Vec::new();
+    for p in lock
+        .get("package")
+        .and_then(|p| p.as_array())
+        .into_iter()
+        .flatten()
+    {
+        let field = |key: &str| p.get(key).and_then(|v| v.as_str()).map(str::to_string);
+        let (Some(name), Some(version)) = (field("name"), field("version")) else {
+            continue;
+        };
+        let dependencies = p
+            .get("dependencies")
+            .and_then(|d| d.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|d| d.as_str().map(str::to_string))
+            .collect();
+        packages.push(LockedPackage {
+            name,
+            version,
+            source: field("source"),
+            dependencies,
+        });
+    }
+    Ok(packages)
+}
@@ -0,0 +1,580 @@
+use std::collections::HashSet;
+
+use anyhow::anyhow;
+use proc_macro2::Span;
+use quote::ToTokens;
+use syn::{
+    braced, parse::ParseStream, punctuated::Punctuated, spanned::Spanned, token::Brace, AttrStyle,
+    Attribute, Expr, ExprLit, Item, ItemMacro, Lit, Meta, Token,
+};
+
+use crate::{rewrite::Edit, Result};
+
+/// A set of configuration options used to evaluate `cfg` predicates.
+///
+/// A predicate that refers to a key not in the set (such as `windows` when only `unix` is set) cannot be decided.
+#[derive(Clone, Debug, Default)]
+pub struct CfgSet {
+    options: HashSet<(String, Option<String>)>,
+    keys: HashSet<String>,
+}
+
+impl CfgSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enables `key`, or `key = "value"` if `value` is `Some`.
+    pub fn insert(&mut self, key: &str, value: Option<&str>) {
+        self.keys.insert(key.to_string());
+        self.options
+            .insert((key.to_string(), value.map(|v| v.to_string())));
+    }
+
+    /// Makes `key` known without enabling any value, so that predicates on it evaluate to `false`.
+    pub fn declare(&mut self, key: &str) {
+        self.keys.insert(key.to_string());
+    }
+
+    /// Enables an option written as in `--cfg` of rustc, such as `unix` or `feature="std"`.
+    pub fn insert_str(&mut self, s: &str) -> Result<()> {
+        let e = || anyhow!("invalid cfg option : `{s}`");
+        match syn::parse_str::<Meta>(s).map_err(|_| e())? {
+            Meta::Path(path) => {
+                let key = path.get_ident().ok_or_else(e)?;
+                self.insert(&key.to_string(), None);
+            }
+            Meta::NameValue(nv) => {
+                let key = nv.path.get_ident().ok_or_else(e)?;
+                let Expr::Lit(ExprLit {
+                    lit: Lit::Str(value),
+                    ..
+                }) = &nv.value
+                else {
+                    return Err(e().into());
+                };
+                self.insert(&key.to_string(), Some(&value.value()));
+            }
+            Meta::List(_) => return Err(e().into()),
+        }
+        Ok(())
+    }
+
+    /// Evaluates a `cfg` predicate. Returns `None` if it cannot be decided.
+    pub(crate) fn eval(&self, meta: &Meta) -> Option<bool> {
+        match meta {
+            Meta::Path(path) => {
+                let key = path.get_ident()?.to_string();
+                self.keys
+                    .contains(&key)
+                    .then(|| self.options.contains(&(key, None)))
+            }
+            Meta::NameValue(nv) => {
+                let key = nv.path.get_ident()?.to_string();
+                let Expr::Lit(ExprLit {
+                    lit: Lit::Str(value),
+                    ..
+                }) = &nv.value
+                else {
+                    return None;
+                };
+                self.keys
+                    .contains(&key)
+                    .then(|| self.options.contains(&(key, Some(value.value()))))
+            }
+            Meta::List(list) => {
+                let args = list
+                    .parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+                    .ok()?;
+                let values = args.iter().map(|m| self.eval(m));
+                if list.path.is_ident("all") {
+                    let mut r = Some(true);
+                    for v in values {
+                        match v {
+                            Some(false) => return Some(false),
+                            Some(true) => {}
+                            None => r = None,
+                        }
+                    }
+                    r
+                } else if list.path.is_ident("any") {
+                    let mut r = Some(false);
+                    for v in values {
+                        match v {
+                            Some(true) => return Some(true),
+                            Some(false) => {}
+                            None => r = None,
+                        }
+                    }
+                    r
+                } else if list.path.is_ident("not") && args.len() == 1 {
+                    self.eval(&args[0]).map(|v| !v)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// The result of evaluating the `cfg` and `cfg_attr` attributes of a node.
+#[derive(Default)]
+pub(crate) struct AttrsEval {
+    /// Whether any `cfg` is false, in which case the node is removed.
+    pub removed: bool,
+    /// The edits that remove the `cfg` attributes that are true and replace the `cfg_attr` attributes with the
+    /// attributes they expand to.
+    pub edits: Vec<Edit>,
+    /// The attributes that `cfg_attr` expanded to but `keep` rejected, which are left out of the edits.
+    pub dropped: Vec<Meta>,
+    /// The predicates that cannot be decided, with the keys that make them undecidable.
+    pub undecided: Vec<(Span, Vec<String>)>,
+}
+
+impl CfgSet {
+    /// Evaluates the `cfg` and `cfg_attr` attributes in `attrs`, whose source text is `s`.
+    ///
+    /// Attributes whose predicates cannot be decided are left as they are.
+    pub(crate) fn eval_attrs(
+        &self,
+        s: &str,
+        attrs: &[Attribute],
+        keep: impl Fn(&Meta) -> bool,
+    ) -> AttrsEval {
+        let mut r = AttrsEval::default();
+        for attr in attrs {
+            let range = attr.span().byte_range();
+            let metas = match self.expand_meta(&attr.meta, &keep, &mut r) {
+                Some(metas) => metas,
+                None if r.removed => return r,
+                None => continue,
+            };
+            let mut text = String::new();
+            for meta in &metas {
+                if !text.is_empty() {
+                    text.push(' ');
+                }
+                let pound = match attr.style {
+                    AttrStyle::Outer => "#",
+                    AttrStyle::Inner(_) => "#!",
+                };
+                text.push_str(&format!("{pound}[{}]", &s[meta.span().byte_range()]));
+            }
+            if text.is_empty() {
+                let rest = &s[range.end..];
+                let end = range.end + (rest.len() - rest.trim_start().len());
+                r.edits.push((range.start..end, text));
+            } else {
+                r.edits.push((range, text));
+            }
+        }
+        r
+    }
+
+    /// Evaluates an attribute, and returns the attributes that replace it,
+    /// or `None` if it is kept as it is or the node is removed.
+    fn expand_meta(
+        &self,
+        meta: &Meta,
+        keep: &impl Fn(&Meta) -> bool,
+        r: &mut AttrsEval,
+    ) -> Option<Vec<Meta>> {
+        if meta.path().is_ident("cfg") {
+            let pred = meta.require_list().ok()?.parse_args::<Meta>().ok()?;
+            return match self.eval(&pred) {
+                Some(true) => Some(Vec::new()),
+                Some(false) => {
+                    r.removed = true;
+                    None
+                }
+                None => {
+                    r.undecided.push((meta.span(), self.undecided_keys(&pred)));
+                    None
+                }
+            };
+        }
+        if !meta.path().is_ident("cfg_attr") {
+            return None;
+        }
+        let args = meta
+            .require_list()
+            .ok()?
+            .parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+            .ok()?;
+        let pred = args.first()?;
+        match self.eval(pred) {
+            Some(true) => {}
+            Some(false) => return Some(Vec::new()),
+            None => {
+                r.undecided.push((meta.span(), self.undecided_keys(pred)));
+                return None;
+            }
+        }
+        let mut metas = Vec::new();
+        for meta in args.into_iter().skip(1) {
+            let is_cfg = meta.path().is_ident("cfg") || meta.path().is_ident("cfg_attr");
+            match self.expand_meta(&meta, keep, r) {
+                Some(expanded) => metas.extend(expanded),
+                None if r.removed => return None,
+                None if is_cfg || keep(&meta) => metas.push(meta),
+                None => r.dropped.push(meta),
+            }
+        }
+        Some(metas)
+    }
+
+    /// Returns the keys in `pred` that are not in the set, or the predicates that are not supported.
+    fn undecided_keys(&self, pred: &Meta) -> Vec<String> {
+        let mut keys = Vec::new();
+        match pred {
+            Meta::Path(path) => keys.push(path.to_token_stream().to_string()),
+            Meta::NameValue(nv) => keys.push(nv.path.to_token_stream().to_string()),
+            Meta::List(list) => {
+                let is_op = ["all", "any", "not"]
+                    .iter()
+                    .any(|op| list.path.is_ident(op));
+                match list.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated) {
+                    Ok(args) if is_op => {
+                        for arg in &args {
+                            if self.eval(arg).is_none() {
+                                keys.extend(self.undecided_keys(arg));
+                            }
+                        }
+                    }
+                    _ => keys.push(list.path.to_token_stream().to_string()),
+                }
+            }
+        }
+        keys.retain(|key| !self.keys.contains(key));
+        keys.dedup();
+        keys
+    }
+}
+
+/// A branch of `cfg_if! { if #[cfg(...)] { ... } else { ... } }`.
+pub(crate) struct CfgIfBranch {
+    /// The predicate, or `None` for the `else` branch.
+    pub cfg: Option<Meta>,
+    pub brace: Brace,
+    pub items: Vec<Item>,
+}
+
+/// Returns the branch of `cfg_if!` selected by `cfg`, or `Some(None)` if no branch is selected.
+///
+/// Returns `None` if `cfg` is not given or the conditions cannot be decided, in which case all branches are kept.
+pub(crate) fn select_cfg_if_branch<'a>(
+    branches: &'a [CfgIfBranch],
+    cfg: Option<&CfgSet>,
+) -> Option<Option<&'a CfgIfBranch>> {
+    let cfg = cfg?;
+    for branch in branches {
+        match branch.cfg.as_ref().map_or(Some(true), |m| cfg.eval(m)) {
+            Some(true) => return Some(Some(branch)),
+            Some(false) => {}
+            None => return None,
+        }
+    }
+    Some(None)
+}
+
+/// Returns `items` with the invocations of `cfg_if!` replaced by the items in their branches that are kept in the
+/// expanded crate.
+pub(crate) fn expand_cfg_if(items: &[Item], cfg: Option<&CfgSet>) -> Vec<Item> {
+    expand_cfg_if_with_conditions(items, cfg)
+        .into_iter()
+        .map(|(item, _)| item)
+        .collect()
+}
+
+/// A condition of an item in a branch of `cfg_if!`.
+pub(crate) struct CfgIfCondition {
+    pub pred: Meta,
+    /// Whether `pred` must be false, as the predicate of a preceding branch.
+    pub negated: bool,
+}
+impl CfgIfCondition {
+    /// Returns the condition as an attribute such as `#[cfg(not(unix))]`, where `s` is the source text.
+    pub fn to_attr_text(&self, s: &str) -> String {
+        let pred = s
+            .get(self.pred.span().byte_range())
+            .filter(|t| !t.is_empty())
+            .map_or_else(|| self.pred.to_token_stream().to_string(), str::to_string);
+        if self.negated {
+            format!("#[cfg(not({pred}))]")
+        } else {
+            format!("#[cfg({pred})]")
+        }
+    }
+}
+
+/// Same as [`expand_cfg_if`], but pairs each item with the conditions of the branches containing it, such as
+/// `not(unix)` and `windows` for the items in `else if #[cfg(windows)]` following `if #[cfg(unix)]`.
+pub(crate) fn expand_cfg_if_with_conditions(
+    items: &[Item],
+    cfg: Option<&CfgSet>,
+) -> Vec<(Item, Vec<CfgIfCondition>)> {
+    let mut expanded = Vec::new();
+    for item in items {
+        let Some(branches) = (match item {
+            Item::Macro(i) => parse_cfg_if(i),
+            _ => None,
+        }) else {
+            expanded.push((item.clone(), Vec::new()));
+            continue;
+        };
+        let selected = select_cfg_if_branch(&branches, cfg);
+        for (i, branch) in branches.iter().enumerate() {
+            let kept = match selected {
+                Some(selected) => selected.is_some_and(|b| std::ptr::eq(b, branch)),
+                None => true,
+            };
+            if !kept {
+                continue;
+            }
+            for (item, inner) in expand_cfg_if_with_conditions(&branch.items, cfg) {
+                let mut conditions: Vec<CfgIfCondition> = branches[..i]
+                    .iter()
+                    .filter_map(|b| b.cfg.clone())
+                    .map(|pred| CfgIfCondition {
+                        pred,
+                        negated: true,
+                    })
+                    .collect();
+                conditions.extend(branch.cfg.clone().map(|pred| CfgIfCondition {
+                    pred,
+                    negated: false,
+                }));
+                conditions.extend(inner);
+                expanded.push((item, conditions));
+            }
+        }
+    }
+    expanded
+}
+
+/// Parses the branches if `i` is an invocation of `cfg_if!`.
+pub(crate) fn parse_cfg_if(i: &ItemMacro) -> Option<Vec<CfgIfBranch>> {
+    if i.mac.path.segments.last()?.ident != "cfg_if" {
+        return None;
+    }
+    i.mac.parse_body_with(parse_cfg_if_body).ok()
+}
+fn parse_cfg_if_body(input: ParseStream) -> syn::Result<Vec<CfgIfBranch>> {
+    let mut branches = Vec::new();
+    loop {
+        input.parse::<Token![if]>()?;
+        let attrs = input.call(Attribute::parse_outer)?;
+        let [attr] = &attrs[..] else {
+            return Err(input.error("expected `#[cfg(...)]`"));
+        };
+        if !attr.path().is_ident("cfg") {
+            return Err(input.error("expected `#[cfg(...)]`"));
+        }
+        let cfg = Some(attr.parse_args::<Meta>()?);
+        let (brace, items) = parse_braced_items(input)?;
+        branches.push(CfgIfBranch { cfg, brace, items });
+        if input.is_empty() {
+            break;
+        }
+        input.parse::<Token![else]>()?;
+        if !input.peek(Token![if]) {
+            let (brace, items) = parse_braced_items(input)?;
+            branches.push(CfgIfBranch {
+                cfg: None,
+                brace,
+                items,
+            });
+            break;
+        }
+    }
+    Ok(branches)
+}
+fn parse_braced_items(input: ParseStream) -> syn::Result<(Brace, Vec<Item>)> {
+    let content;
+    let brace = braced!(content in input);
+    let mut items = Vec::new();
+    while !content.is_empty() {
+        items.push(content.parse()?);
+    }
+    Ok((brace, items))
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::ItemStruct;
+
+    use super::*;
+    use crate::rewrite::push_with_edits;
+
+    fn cfg_set(options: &[&str]) -> CfgSet {
+        let mut cfg = CfgSet::new();
+        for option in options {
+            assert!(cfg.insert_str(option).is_ok(), "{option}");
+        }
+        cfg
+    }
+    fn linux() -> CfgSet {
+        cfg_set(&["unix", "target_os=\"linux\""])
+    }
+
+    /// Evaluates the attributes of the struct `s`, and returns the edited text, or `None` if it is removed.
+    fn eval_struct(cfg: &CfgSet, s: &str) -> Option<String> {
+        let item: ItemStruct = syn::parse_str(s).unwrap();
+        let mut eval = cfg.eval_attrs(s, &item.attrs, |m| !m.path().is_ident("path"));
+        if eval.removed {
+            return None;
+        }
+        eval.edits.sort_by_key(|(r, _)| r.start);
+        let mut text = String::new();
+        push_with_edits(&mut text, s, 0..s.len(), &eval.edits);
+        Some(text)
+    }
+
+    #[test]
+    fn eval_predicates() {
+        let cfg = linux();
+        let eval = |s: &str| cfg.eval(&syn::parse_str::<Meta>(s).unwrap());
+        assert_eq!(eval("unix"), Some(true));
+        assert_eq!(eval("target_os = \"linux\""), Some(true));
+        assert_eq!(eval("target_os = \"windows\""), Some(false));
+        assert_eq!(eval("not(unix)"), Some(false));
+        assert_eq!(eval("all(unix, target_os = \"linux\")"), Some(true));
+        assert_eq!(eval("all(windows, target_os = \"windows\")"), Some(false));
+        assert_eq!(eval("any(windows, unix)"), Some(true));
+        assert_eq!(eval("all(windows, unix)"), None);
+        assert_eq!(eval("windows"), None);
+        assert_eq!(eval("unknown(unix)"), None);
+    }
+
+    #[test]
+    fn declared_key_without_value_is_false() {
+        let mut cfg = CfgSet::new();
+        cfg.declare("feature");
+        cfg.insert("feature", Some("std"));
+        let eval = |s: &str| cfg.eval(&syn::parse_str::<Meta>(s).unwrap());
+        assert_eq!(eval("feature = \"std\""), Some(true));
+        assert_eq!(eval("feature = \"alloc\""), Some(false));
+    }
+
+    #[test]
+    fn true_cfg_is_removed() {
+        let s = "#[cfg(unix)]\n#[derive(Debug)]\nstruct S;";
+        assert_eq!(
+            eval_struct(&linux(), s).as_deref(),
+            Some("#[derive(Debug)]\nstruct S;")
+        );
+    }
+
+    #[test]
+    fn false_cfg_removes_node() {
+        let s = "#[derive(Debug)]\n#[cfg(not(unix))]\nstruct S;";
+        assert_eq!(eval_struct(&linux(), s), None);
+    }
+
+    #[test]
+    fn true_cfg_attr_is_replaced() {
+        let s = "#[cfg_attr(unix, derive(Debug), allow(dead_code))]\nstruct S;";
+        assert_eq!(
+            eval_struct(&linux(), s).as_deref(),
+            Some("#[derive(Debug)] #[allow(dead_code)]\nstruct S;")
+        );
+    }
+
+    #[test]
+    fn false_cfg_attr_is_removed() {
+        let s = "#[cfg_attr(target_os = \"windows\", derive(Debug))]\nstruct S;";
+        assert_eq!(eval_struct(&linux(), s).as_deref(), Some("struct S;"));
+    }
+
+    #[test]
+    fn cfg_in_cfg_attr() {
+        let s = "#[cfg_attr(unix, cfg(target_os = \"windows\"))]\nstruct S;";
+        assert_eq!(eval_struct(&linux(), s), None);
+        let s = "#[cfg_attr(unix, cfg_attr(unix, derive(Debug)))]\nstruct S;";
+        assert_eq!(
+            eval_struct(&linux(), s).as_deref(),
+            Some("#[derive(Debug)]\nstruct S;")
+        );
+    }
+
+    #[test]
+    fn undecided_cfg_is_kept() {
+        let s =
+            "#[cfg(all(unix, windows))]\n#[cfg_attr(feature = \"std\", derive(Debug))]\nstruct S;";
+        let item: ItemStruct = syn::parse_str(s).unwrap();
+        let eval = linux().eval_attrs(s, &item.attrs, |_| true);
+        assert!(!eval.removed);
+        assert!(eval.edits.is_empty());
+        let keys: Vec<Vec<String>> = eval.undecided.into_iter().map(|(_, k)| k).collect();
+        assert_eq!(keys, [vec!["windows"], vec!["feature"]]);
+    }
+
+    #[test]
+    fn rejected_attrs_are_dropped() {
+        let s = "#[cfg_attr(unix, path = \"unix.rs\", allow(dead_code))]\nstruct S;";
+        let item: ItemStruct = syn::parse_str(s).unwrap();
+        let eval = linux().eval_attrs(s, &item.attrs, |m| !m.path().is_ident("path"));
+        assert_eq!(eval.dropped.len(), 1);
+        assert!(eval.dropped[0].path().is_ident("path"));
+        assert_eq!(
+            eval_struct(&linux(), s).as_deref(),
+            Some("#[allow(dead_code)]\nstruct S;")
+        );
+    }
+
+    #[test]
+    fn cfg_if_conditions() {
+        let s = "cfg_if! {
+    if #[cfg(unix)] {
+        fn a() {}
+    } else if #[cfg(windows)] {
+        fn b() {}
+    } else {
+        fn c() {}
+    }
+}
+";
+        let file = syn::parse_file(s).unwrap();
+        let conditions = |cfg: Option<&CfgSet>| -> Vec<(String, Vec<String>)> {
+            expand_cfg_if_with_conditions(&file.items, cfg)
+                .into_iter()
+                .map(|(item, conditions)| {
+                    let Item::Fn(f) = item else {
+                        panic!("unexpected item");
+                    };
+                    let conditions = conditions.iter().map(|c| c.to_attr_text(s)).collect();
+                    (f.sig.ident.to_string(), conditions)
+                })
+                .collect()
+        };
+        assert_eq!(
+            conditions(None),
+            [
+                ("a".to_string(), vec!["#[cfg(unix)]".to_string()]),
+                (
+                    "b".to_string(),
+                    vec![
+                        "#[cfg(not(unix))]".to_string(),
+                        "#[cfg(windows)]".to_string()
+                    ]
+                ),
+                (
+                    "c".to_string(),
+                    vec![
+                        "#[cfg(not(unix))]".to_string(),
+                        "#[cfg(not(windows))]".to_string()
+                    ]
+                ),
+            ]
+        );
+        let names = |cfg: &CfgSet| -> Vec<String> {
+            conditions(Some(cfg)).into_iter().map(|(n, _)| n).collect()
+        };
+        assert_eq!(names(&linux()), ["a"]);
+        assert_eq!(names(&cfg_set(&["target_os=\"linux\""])), ["a", "b", "c"]);
+        let mut cfg = CfgSet::new();
+        cfg.declare("unix");
+        cfg.declare("windows");
+        assert_eq!(names(&cfg), ["c"]);
+    }
+}
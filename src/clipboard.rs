@@ -0,0 +1,99 @@
+use std::{
+    io::{self, IsTerminal, Write},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+/// The method used to copy the result to the clipboard.
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+pub enum ClipboardBackend {
+    /// Use `arboard`, and fall back to `command` (if configured) or `osc52` when it fails.
+    #[default]
+    Auto,
+    /// Use the system clipboard through `arboard`.
+    Arboard,
+    /// Write an OSC 52 escape sequence to the terminal.
+    Osc52,
+    /// Pipe the text to the command given by `--clipboard-command`.
+    Command,
+}
+
+pub fn set_text(backend: ClipboardBackend, command: Option<&str>, text: &str) -> Result<()> {
+    match backend {
+        ClipboardBackend::Auto => {
+            let Err(e) = set_text_arboard(text) else {
+                return Ok(());
+            };
+            let r = if let Some(command) = command {
+                set_text_command(command, text)
+            } else {
+                set_text_osc52(text)
+            };
+            r.map_err(|e2| anyhow!("Could not copy to clipboard ({e}) ({e2})"))
+        }
+        ClipboardBackend::Arboard => set_text_arboard(text),
+        ClipboardBackend::Osc52 => set_text_osc52(text),
+        ClipboardBackend::Command => match command {
+            Some(command) => set_text_command(command, text),
+            None => bail!("`--clipboard-backend command` requires `--clipboard-command`"),
+        },
+    }
+}
+
+fn set_text_arboard(text: &str) -> Result<()> {
+    arboard::Clipboard::new()?.set_text(text)?;
+    Ok(())
+}
+
+fn set_text_osc52(text: &str) -> Result<()> {
+    let seq = format!("\x1b]52;c;{}\x07", base64(text.as_bytes()));
+    if io::stderr().is_terminal() {
+        io::stderr().write_all(seq.as_bytes())?;
+    } else if io::stdout().is_terminal() {
+        io::stdout().write_all(seq.as_bytes())?;
+    } else {
+        bail!("OSC 52 requires a terminal");
+    }
+    Ok(())
+}
+
+/// Runs `command` (a program followed by whitespace separated arguments) with `text` as its stdin.
+fn set_text_command(command: &str, text: &str) -> Result<()> {
+    let mut args = command.split_whitespace();
+    let program = args
+        .next()
+        .ok_or_else(|| anyhow!("clipboard command is empty"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Could not run `{program}` ({e})"))?;
+    child.stdin.take().unwrap().write_all(text.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("`{command}` failed ({status})");
+    }
+    Ok(())
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut s = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(CHARS[(n >> (18 - i * 6)) as usize & 63] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
@@ -0,0 +1,305 @@
+use std::{collections::BTreeSet, path::Path};
+
+use syn::{visit::Visit, Item, ItemMod, ItemUse, UseTree, Visibility};
+
+use crate::{
+    absolute_path, expand_cfg_if, parse_file, path_from_mod, shebang_end, with_source, Expander,
+    FileKind, Result,
+};
+
+/// The dependencies between the modules of a crate, collected from paths starting with `crate`, `super` or `self`.
+#[derive(Debug)]
+pub struct ModuleGraph {
+    /// The paths of the modules such as `crate::a::b`.
+    pub modules: Vec<String>,
+    /// Pairs of the indexes into `modules` of the referring module and the referred module.
+    pub edges: Vec<(usize, usize)>,
+}
+
+impl ModuleGraph {
+    /// Returns the strongly connected components that consist of two or more modules.
+    pub fn cycles(&self) -> Vec<Vec<usize>> {
+        let mut t = Tarjan::new(self);
+        for v in 0..self.modules.len() {
+            if t.index[v].is_none() {
+                t.visit(v);
+            }
+        }
+        t.components.retain(|c| c.len() > 1);
+        t.components
+    }
+
+    /// Returns the index of the cycle containing each module.
+    fn cycle_ids(&self) -> Vec<Option<usize>> {
+        let mut ids = vec![None; self.modules.len()];
+        for (i, c) in self.cycles().into_iter().enumerate() {
+            for v in c {
+                ids[v] = Some(i);
+            }
+        }
+        ids
+    }
+
+    /// Renders the graph in the Graphviz DOT language. Modules and references in cycles are colored red.
+    pub fn to_dot(&self) -> String {
+        let ids = self.cycle_ids();
+        let mut s = String::from("digraph modules {\n");
+        for (i, m) in self.modules.iter().enumerate() {
+            let attrs = if ids[i].is_some() { " [color=red]" } else { "" };
+            s.push_str(&format!("    \"{m}\"{attrs};\n"));
+        }
+        for &(from, to) in &self.edges {
+            let attrs = if ids[from].is_some() && ids[from] == ids[to] {
+                " [color=red]"
+            } else {
+                ""
+            };
+            s.push_str(&format!(
+                "    \"{}\" -> \"{}\"{attrs};\n",
+                self.modules[from], self.modules[to]
+            ));
+        }
+        s.push_str("}\n");
+        s
+    }
+
+    /// Renders the graph as a Mermaid flowchart. Modules and references in cycles are colored red.
+    pub fn to_mermaid(&self) -> String {
+        let ids = self.cycle_ids();
+        let mut s = String::from("graph LR\n");
+        for (i, m) in self.modules.iter().enumerate() {
+            s.push_str(&format!("    m{i}[\"{m}\"]\n"));
+        }
+        for (i, &(from, to)) in self.edges.iter().enumerate() {
+            s.push_str(&format!("    m{from} --> m{to}\n"));
+            if ids[from].is_some() && ids[from] == ids[to] {
+                s.push_str(&format!("    linkStyle {i} stroke:red\n"));
+            }
+        }
+        for (i, id) in ids.iter().enumerate() {
+            if id.is_some() {
+                s.push_str(&format!("    style m{i} stroke:red\n"));
+            }
+        }
+        s
+    }
+}
+
+struct Tarjan<'a> {
+    g: &'a ModuleGraph,
+    next: usize,
+    index: Vec<Option<usize>>,
+    low: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    components: Vec<Vec<usize>>,
+}
+impl<'a> Tarjan<'a> {
+    fn new(g: &'a ModuleGraph) -> Self {
+        let n = g.modules.len();
+        Self {
+            g,
+            next: 0,
+            index: vec![None; n],
+            low: vec![0; n],
+            stack: Vec::new(),
+            on_stack: vec![false; n],
+            components: Vec::new(),
+        }
+    }
+    fn visit(&mut self, v: usize) {
+        self.index[v] = Some(self.next);
+        self.low[v] = self.next;
+        self.next += 1;
+        self.stack.push(v);
+        self.on_stack[v] = true;
+        for &(from, to) in &self.g.edges {
+            if from != v {
+                continue;
+            }
+            match self.index[to] {
+                None => {
+                    self.visit(to);
+                    self.low[v] = self.low[v].min(self.low[to]);
+                }
+                Some(index) if self.on_stack[to] => self.low[v] = self.low[v].min(index),
+                Some(_) => {}
+            }
+        }
+        if Some(self.low[v]) == self.index[v] {
+            let mut c = Vec::new();
+            while let Some(w) = self.stack.pop() {
+                self.on_stack[w] = false;
+                c.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            c.sort();
+            self.components.push(c);
+        }
+    }
+}
+
+impl Expander {
+    /// Returns the dependencies between the modules of the crate whose root file is `path`.
+    ///
+    /// Modules are resolved in the same way as [`Expander::expand`].
+    pub fn module_graph(&mut self, path: &Path) -> Result<ModuleGraph> {
+        let root = absolute_path(path)?.parent().unwrap().to_path_buf();
+        let mut refs = Vec::new();
+        self.collect_refs_from_path(
+            &root,
+            path,
+            FileKind::CrateRoot,
+            &["crate".to_string()],
+            &mut refs,
+        )?;
+
+        let modules: Vec<String> = refs.iter().map(|(m, _)| m.join("::")).collect();
+        let mut edges = BTreeSet::new();
+        for (from, (_, paths)) in refs.iter().enumerate() {
+            for path in paths {
+                let to = (1..=path.len())
+                    .rev()
+                    .find_map(|n| refs.iter().position(|(m, _)| m[..] == path[..n]));
+                if let Some(to) = to {
+                    if to != from {
+                        edges.insert((from, to));
+                    }
+                }
+            }
+        }
+        Ok(ModuleGraph {
+            modules,
+            edges: edges.into_iter().collect(),
+        })
+    }
+
+    /// Collects the modules in the file and the absolute paths they refer to.
+    fn collect_refs_from_path(
+        &mut self,
+        root: &Path,
+        path: &Path,
+        kind: FileKind,
+        module: &[String],
+        refs: &mut Vec<(Vec<String>, Vec<Vec<String>>)>,
+    ) -> Result<()> {
+        let s = self.read_file(root, path)?;
+        let r = (|| {
+            let file = parse_file(&s, shebang_end(&s))?;
+            self.collect_refs_from_items(root, path, Some(kind), module, &file.items, refs)
+        })();
+        with_source(r, path, &s)
+    }
+    /// Collects the modules in `items` and the absolute paths they refer to.
+    ///
+    /// `kind` is `None` if `items` are in an inline module, where `mod name;` is left as it is by
+    /// [`Expander::expand`] and therefore ignored.
+    fn collect_refs_from_items(
+        &mut self,
+        root: &Path,
+        path: &Path,
+        kind: Option<FileKind>,
+        module: &[String],
+        items: &[Item],
+        refs: &mut Vec<(Vec<String>, Vec<Vec<String>>)>,
+    ) -> Result<()> {
+        let items = &expand_cfg_if(items, self.cfg.as_ref());
+        let mut c = RefCollector {
+            module,
+            paths: Vec::new(),
+        };
+        for item in items {
+            c.visit_item(item);
+        }
+        refs.push((module.to_vec(), c.paths));
+        for item in items {
+            let Item::Mod(m) = item else {
+                continue;
+            };
+            let mut child = module.to_vec();
+            child.push(m.ident.to_string());
+            if let Some((_, items)) = &m.content {
+                self.collect_refs_from_items(root, path, None, &child, items, refs)?;
+            } else if let Some(kind) = kind {
+                let mod_path = path_from_mod(path, kind.owns_dir(), m, |p| self.is_file(p))?;
+                self.collect_refs_from_path(root, &mod_path, FileKind::of_mod(m), &child, refs)
+                    .map_err(|e| e.included_from(path, m))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct RefCollector<'a> {
+    module: &'a [String],
+    paths: Vec<Vec<String>>,
+}
+impl RefCollector<'_> {
+    /// Converts a path relative to the module into an absolute path, if it starts with `crate`, `super` or `self`.
+    fn push(&mut self, segments: &[String]) {
+        let mut path = match segments.first().map(|s| s.as_str()) {
+            Some("crate") => vec!["crate".to_string()],
+            Some("self") => self.module.to_vec(),
+            Some("super") => self.module.to_vec(),
+            _ => return,
+        };
+        let mut rest = &segments[1..];
+        if segments[0] == "super" {
+            path.pop();
+        }
+        while let Some(("super", tail)) = rest.split_first().map(|(s, t)| (s.as_str(), t)) {
+            path.pop();
+            rest = tail;
+        }
+        if path.is_empty() {
+            return;
+        }
+        path.extend(rest.iter().cloned());
+        self.paths.push(path);
+    }
+    fn push_use_tree(&mut self, prefix: &mut Vec<String>, tree: &UseTree) {
+        match tree {
+            UseTree::Path(p) => {
+                prefix.push(p.ident.to_string());
+                self.push_use_tree(prefix, &p.tree);
+                prefix.pop();
+            }
+            UseTree::Name(n) => {
+                if n.ident != "self" {
+                    prefix.push(n.ident.to_string());
+                    self.push(prefix);
+                    prefix.pop();
+                } else {
+                    self.push(prefix);
+                }
+            }
+            UseTree::Rename(r) => {
+                prefix.push(r.ident.to_string());
+                self.push(prefix);
+                prefix.pop();
+            }
+            UseTree::Glob(_) => self.push(prefix),
+            UseTree::Group(g) => {
+                for tree in &g.items {
+                    self.push_use_tree(prefix, tree);
+                }
+            }
+        }
+    }
+}
+impl<'ast> Visit<'ast> for RefCollector<'_> {
+    fn visit_item_mod(&mut self, _: &'ast ItemMod) {}
+    fn visit_item_use(&mut self, i: &'ast ItemUse) {
+        self.push_use_tree(&mut Vec::new(), &i.tree);
+    }
+    fn visit_visibility(&mut self, _: &'ast Visibility) {}
+    fn visit_path(&mut self, p: &'ast syn::Path) {
+        if p.leading_colon.is_none() && p.segments.len() > 1 {
+            let segments: Vec<String> = p.segments.iter().map(|s| s.ident.to_string()).collect();
+            self.push(&segments);
+        }
+        syn::visit::visit_path(self, p);
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use syn::{spanned::Spanned, File, Item, ItemExternCrate, Visibility};
+
+use crate::{
+    rewrite::{Edit, ExternCrate},
+    ExpandError, ExpandWarning, Expander, FileKind,
+};
+
+impl Expander {
+    /// Converts the top-level `extern crate` items of a file, which only work as intended at the crate root,
+    /// and returns the edits replacing them.
+    ///
+    /// - `extern crate self as name;` in a bundled library becomes `use crate::library as name;`.
+    /// - `extern crate dep;` referring to a bundled crate becomes `use crate::dep;`.
+    ///   The macros imported by `#[macro_use]` are invoked by their paths instead.
+    /// - Other `extern crate` items at the root of a bundled library, and those with `#[macro_use]` in other
+    ///   non-root files, are hoisted to the crate root.
+    ///
+    /// In a bundled library, the names bound at its root are added to the bundled crates that paths are rewritten
+    /// to, so that they are available in all of its modules as in the extern prelude.
+    pub(crate) fn convert_extern_crates(
+        &mut self,
+        path: &Path,
+        kind: FileKind,
+        s: &str,
+        file: &File,
+    ) -> Vec<Edit> {
+        let crate_path = self.rewrite.crate_path.clone();
+        let is_library_root = kind == FileKind::MergedRoot && crate_path.is_some();
+        let mut edits = Vec::new();
+        for item in &file.items {
+            let Item::ExternCrate(i) = item else {
+                continue;
+            };
+            let macro_use = i.attrs.iter().any(|a| a.path().is_ident("macro_use"));
+            let name = i.rename.as_ref().map_or(&i.ident, |(_, rename)| rename);
+            let range = i.span().byte_range();
+            if i.ident == "self" {
+                let Some(crate_path) = crate_path.as_ref().filter(|_| is_library_root) else {
+                    continue;
+                };
+                if macro_use {
+                    self.warnings.push(ExpandWarning(
+                        ExpandError::new(
+                            Some(i.span()),
+                            anyhow!("`#[macro_use]` on `extern crate self` cannot be converted and was removed"),
+                        )
+                        .with_source(path, s),
+                    ));
+                }
+                let ext = ExternCrate {
+                    path: crate_path.clone(),
+                    macros: Vec::new(),
+                };
+                self.rewrite.externs.insert(name.to_string(), ext);
+                edits.push((range, use_item(s, i, crate_path)));
+            } else if let Some(ext) = self.rewrite.externs.get(&i.ident.to_string()).cloned() {
+                let use_text = use_item(s, i, &ext.path);
+                if macro_use {
+                    self.rewrite.macro_use.extend(ext.macros.iter().cloned());
+                }
+                if is_library_root && name != &i.ident {
+                    self.rewrite.externs.insert(name.to_string(), ext);
+                }
+                edits.push((range, use_text));
+            } else if is_library_root || macro_use && kind != FileKind::CrateRoot {
+                edits.push((range, String::new()));
+                // `cfg` is evaluated here since the features of the crate are not available at the crate root.
+                let Some((text, conditional)) = self.hoisted_text(s, i) else {
+                    continue;
+                };
+                let hoisted = HoistedExternCrate {
+                    krate: i.ident.to_string(),
+                    name: name.to_string(),
+                    macro_use,
+                    conditional,
+                    text,
+                };
+                match self
+                    .extern_crates
+                    .iter_mut()
+                    .find(|c| c.name == hoisted.name)
+                {
+                    Some(c) if c.krate != hoisted.krate => {
+                        self.warnings.push(ExpandWarning(
+                            ExpandError::new(
+                                Some(i.span()),
+                                anyhow!(
+                                    "`extern crate` cannot be moved to the crate root because `{name}` is already bound to `{}`",
+                                    c.krate
+                                ),
+                            )
+                            .with_source(path, s),
+                        ));
+                        continue;
+                    }
+                    // `#[macro_use]` is kept if any of the items has it, and then items without `cfg` are preferred.
+                    Some(c)
+                        if (hoisted.macro_use, !hoisted.conditional)
+                            > (c.macro_use, !c.conditional) =>
+                    {
+                        *c = hoisted
+                    }
+                    Some(_) => {}
+                    None => self.extern_crates.push(hoisted),
+                }
+            }
+        }
+        edits
+    }
+
+    /// Returns the text of `i` moved to the crate root with its `cfg` and `cfg_attr` attributes evaluated,
+    /// and whether it still has `cfg`, or `None` if its `cfg` is false.
+    fn hoisted_text(&self, s: &str, i: &ItemExternCrate) -> Option<(String, bool)> {
+        let edits = match &self.crate_cfg {
+            Some(cfg) => {
+                let eval = cfg.eval_attrs(s, &i.attrs, |_| true);
+                if eval.removed {
+                    return None;
+                }
+                eval.edits
+            }
+            None => Vec::new(),
+        };
+        let mut conditional = false;
+        let mut text = String::new();
+        for attr in &i.attrs {
+            let range = attr.span().byte_range();
+            match edits.iter().find(|(r, _)| r.start == range.start) {
+                Some((_, t)) if t.is_empty() => continue,
+                Some((_, t)) => text.push_str(t),
+                None => {
+                    conditional |= attr.path().is_ident("cfg");
+                    text.push_str(&s[range]);
+                }
+            }
+            text.push('\n');
+        }
+        let start = match i.vis {
+            Visibility::Inherited => i.extern_token.span.byte_range().start,
+            _ => i.vis.span().byte_range().start,
+        };
+        text.push_str(&s[start..i.span().byte_range().end]);
+        Some((text, conditional))
+    }
+}
+
+/// An `extern crate` item moved to the crate root.
+pub(crate) struct HoistedExternCrate {
+    pub krate: String,
+    /// The name that the item binds.
+    pub name: String,
+    pub macro_use: bool,
+    /// Whether the item has `cfg` that cannot be decided.
+    pub conditional: bool,
+    pub text: String,
+}
+
+/// Returns the text of `use path as name;` that replaces `i`, keeping its visibility and attributes
+/// other than `#[macro_use]`.
+fn use_item(s: &str, i: &ItemExternCrate, path: &str) -> String {
+    let mut text = String::new();
+    if matches!(i.vis, Visibility::Inherited) {
+        // Paths using the name are rewritten, so the import is usually unused.
+        text.push_str("#[allow(unused_imports)]\n");
+    }
+    for attr in &i.attrs {
+        if !attr.path().is_ident("macro_use") {
+            text.push_str(&s[attr.span().byte_range()]);
+            text.push('\n');
+        }
+    }
+    if !matches!(i.vis, Visibility::Inherited) {
+        text.push_str(&s[i.vis.span().byte_range()]);
+        text.push(' ');
+    }
+    text.push_str(&format!("use {path}"));
+    if let Some((_, rename)) = &i.rename {
+        text.push_str(&format!(" as {rename}"));
+    }
+    text.push(';');
+    text
+}
@@ -0,0 +1,239 @@
+use proc_macro2::Span;
+use syn::{
+    punctuated::Punctuated, visit::Visit, Attribute, Block, Expr, File, Ident, Item,
+    ItemExternCrate, ItemMacro, ItemMod, ItemUse, Macro, Stmt, Token, UseTree, VisRestricted,
+};
+
+use crate::{
+    cfg::{expand_cfg_if, parse_cfg_if},
+    type_namespace_names, use_tree_names,
+};
+
+const PRIMITIVE_TYPES: &[&str] = &[
+    "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64",
+    "i128", "isize", "f32", "f64",
+];
+
+/// Returns the references to external crates in `file` that `is_allowed` rejects, as pairs of their spans and
+/// crate names.
+///
+/// `extern crate` items, paths starting with `::` and paths whose first segment is not defined in the enclosing
+/// module or block are the references. Names in scopes with glob imports cannot be told apart from crates,
+/// so only the first two kinds are reported there.
+///
+/// In the 2015 edition, `use` paths and paths starting with `::` are relative to the crate root, where external
+/// crates other than `std` and `core` are brought in by `extern crate`, so only the `extern crate` items are reported.
+pub(crate) fn external_crate_refs(
+    file: &File,
+    edition_2015: bool,
+    is_allowed: impl Fn(&str) -> bool,
+) -> Vec<(Span, String)> {
+    let mut v = ExternRefCollector {
+        is_allowed,
+        edition_2015,
+        scopes: vec![Scope::new(&file.items, true)],
+        refs: Vec::new(),
+    };
+    v.visit_file(file);
+    v.refs
+}
+
+struct Scope {
+    names: Vec<String>,
+    has_glob: bool,
+    is_module: bool,
+}
+impl Scope {
+    fn new(items: &[Item], is_module: bool) -> Self {
+        // Items in the branches of `cfg_if!` are defined in the enclosing scope.
+        let items = &expand_cfg_if(items, None);
+        let has_glob = items
+            .iter()
+            .any(|item| matches!(item, Item::Use(i) if has_glob(&i.tree)));
+        Self {
+            names: type_namespace_names(items),
+            has_glob,
+            is_module,
+        }
+    }
+}
+fn has_glob(tree: &UseTree) -> bool {
+    match tree {
+        UseTree::Path(p) => has_glob(&p.tree),
+        UseTree::Glob(_) => true,
+        UseTree::Group(g) => g.items.iter().any(has_glob),
+        UseTree::Name(_) | UseTree::Rename(_) => false,
+    }
+}
+
+/// Collects the first segments of the paths in `tree`, looking into the groups at its root.
+fn use_tree_firsts<'a>(tree: &'a UseTree, firsts: &mut Vec<&'a Ident>) {
+    match tree {
+        UseTree::Path(p) => firsts.push(&p.ident),
+        UseTree::Name(n) => firsts.push(&n.ident),
+        UseTree::Rename(r) => firsts.push(&r.ident),
+        UseTree::Group(g) => {
+            for tree in &g.items {
+                use_tree_firsts(tree, firsts);
+            }
+        }
+        UseTree::Glob(_) => {}
+    }
+}
+
+struct ExternRefCollector<F> {
+    is_allowed: F,
+    edition_2015: bool,
+    scopes: Vec<Scope>,
+    refs: Vec<(Span, String)>,
+}
+impl<F: Fn(&str) -> bool> ExternRefCollector<F> {
+    /// Checks a path whose first segment is `first`. `absolute` is true for paths starting with `::`.
+    ///
+    /// `own_names` are the names defined by the `use` declaration containing the path, such as `anyhow` in
+    /// `use anyhow::anyhow;`, which do not make the first segment a local name.
+    fn check(&mut self, first: &Ident, absolute: bool, own_names: &[String]) {
+        let name = first.to_string();
+        if (self.is_allowed)(&name) {
+            return;
+        }
+        if !absolute {
+            if ["crate", "self", "super", "Self"].contains(&name.as_str())
+                || PRIMITIVE_TYPES.contains(&name.as_str())
+                || name.starts_with(|c: char| c.is_ascii_uppercase())
+            {
+                return;
+            }
+            // Items are visible in the blocks nested in a module, but not in the modules nested in it.
+            let own = own_names.iter().filter(|n| **n == name).count();
+            for (i, scope) in self.scopes.iter().rev().enumerate() {
+                let count = scope.names.iter().filter(|n| **n == name).count();
+                if scope.has_glob || count > if i == 0 { own } else { 0 } {
+                    return;
+                }
+                if scope.is_module {
+                    break;
+                }
+            }
+        }
+        self.refs.push((first.span(), name));
+    }
+}
+impl<'ast, F: Fn(&str) -> bool> Visit<'ast> for ExternRefCollector<F> {
+    fn visit_item_mod(&mut self, i: &'ast ItemMod) {
+        if let Some((_, items)) = &i.content {
+            self.scopes.push(Scope::new(items, true));
+            syn::visit::visit_item_mod(self, i);
+            self.scopes.pop();
+        }
+    }
+    fn visit_block(&mut self, i: &'ast Block) {
+        let items: Vec<Item> = i
+            .stmts
+            .iter()
+            .filter_map(|s| match s {
+                Stmt::Item(item) => Some(item.clone()),
+                _ => None,
+            })
+            .collect();
+        self.scopes.push(Scope::new(&items, false));
+        syn::visit::visit_block(self, i);
+        self.scopes.pop();
+    }
+    fn visit_item_extern_crate(&mut self, i: &'ast ItemExternCrate) {
+        if i.ident != "self" {
+            self.check(&i.ident, true, &[]);
+        }
+    }
+    fn visit_item_use(&mut self, i: &'ast ItemUse) {
+        if self.edition_2015 {
+            return;
+        }
+        let mut own_names = Vec::new();
+        use_tree_names(&i.tree, &mut own_names);
+        let mut firsts = Vec::new();
+        use_tree_firsts(&i.tree, &mut firsts);
+        for first in firsts {
+            self.check(first, i.leading_colon.is_some(), &own_names);
+        }
+    }
+    fn visit_path(&mut self, p: &'ast syn::Path) {
+        if let Some(first) = p.segments.first() {
+            let absolute = p.leading_colon.is_some();
+            if (absolute && !self.edition_2015) || (!absolute && p.segments.len() > 1) {
+                self.check(&first.ident, absolute, &[]);
+            }
+        }
+        syn::visit::visit_path(self, p);
+    }
+    fn visit_vis_restricted(&mut self, _: &'ast VisRestricted) {}
+    fn visit_attribute(&mut self, _: &'ast Attribute) {}
+    fn visit_item_macro(&mut self, i: &'ast ItemMacro) {
+        if let Some(branches) = parse_cfg_if(i) {
+            self.visit_path(&i.mac.path);
+            for branch in &branches {
+                for item in &branch.items {
+                    self.visit_item(item);
+                }
+            }
+        } else if !i.mac.path.is_ident("macro_rules") {
+            self.visit_macro(&i.mac);
+        }
+    }
+    fn visit_macro(&mut self, m: &'ast Macro) {
+        self.visit_path(&m.path);
+        if let Ok(args) = m.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated) {
+            for arg in &args {
+                self.visit_expr(arg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs(s: &str) -> Vec<String> {
+        refs_in_edition(s, false)
+    }
+    fn refs_in_edition(s: &str, edition_2015: bool) -> Vec<String> {
+        let file = syn::parse_file(s).unwrap();
+        external_crate_refs(&file, edition_2015, |name| name == "std")
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    #[test]
+    fn local_names_are_not_refs() {
+        let s = "use std::fmt;\nmod a { pub fn f() {} }\nfn g() { a::f(); fmt::Debug; }\n";
+        assert_eq!(refs(s), Vec::<String>::new());
+    }
+
+    #[test]
+    fn refs_in_use_groups() {
+        assert_eq!(refs("use {foo::a, bar::b};\n"), ["foo", "bar"]);
+    }
+
+    #[test]
+    fn refs_in_cfg_if_branches() {
+        let s = "cfg_if::cfg_if! {
+    if #[cfg(unix)] {
+        mod imp;
+        pub use imp::f;
+    } else {
+        pub fn f() { other::g() }
+    }
+}
+";
+        assert_eq!(refs(s), ["cfg_if", "other"]);
+    }
+
+    #[test]
+    fn paths_relative_to_crate_root_in_2015() {
+        let s = "extern crate foo;\nmod a {\n    use inner::X;\n    fn f() { ::inner::g(); ::foo::h(); }\n}\n";
+        assert_eq!(refs_in_edition(s, true), ["foo"]);
+        assert_eq!(refs_in_edition(s, false), ["foo", "inner", "inner", "foo"]);
+    }
+}
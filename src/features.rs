@@ -0,0 +1,147 @@
+use std::collections::BTreeSet;
+
+use anyhow::anyhow;
+use toml::{Table, Value};
+
+use crate::{bundle::dependency_entries, Result};
+
+/// The features of the crate selected like the feature flags of Cargo.
+#[derive(Clone, Debug, Default)]
+pub struct FeatureSelection {
+    /// Features to enable, which may also be in the form of `dep/feature`.
+    pub features: Vec<String>,
+    pub all_features: bool,
+    pub no_default_features: bool,
+}
+
+/// The features of a package enabled by a set of requested features.
+#[derive(Default)]
+pub(crate) struct EnabledFeatures {
+    pub features: BTreeSet<String>,
+    /// The optional dependencies enabled by the features, by their keys in `Cargo.toml`.
+    pub deps: BTreeSet<String>,
+    /// The features of the dependencies enabled by `dep/feature` or `dep?/feature`,
+    /// which take effect only if the dependency is enabled.
+    pub dep_features: Vec<(String, String)>,
+}
+
+/// Returns the features of the package whose manifest is `manifest` enabled by the selection.
+pub(crate) fn select_features(
+    manifest: &Table,
+    selection: &FeatureSelection,
+) -> Result<EnabledFeatures> {
+    let mut requested = selection.features.clone();
+    if !selection.no_default_features {
+        requested.push("default".to_string());
+    }
+    if selection.all_features {
+        requested.extend(feature_table(manifest).keys().cloned());
+        requested.extend(implicit_features(manifest));
+    }
+    enable_features(manifest, requested)
+}
+
+/// Returns the features requested by a dependency entry such as `foo = { version = "1", features = ["std"] }`.
+pub(crate) fn requested_features(value: &Value) -> Vec<String> {
+    let mut features: Vec<String> = value
+        .get("features")
+        .and_then(|f| f.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f.as_str().map(str::to_string))
+        .collect();
+    let default = ["default-features", "default_features"]
+        .iter()
+        .find_map(|k| value.get(k).and_then(|v| v.as_bool()));
+    if default != Some(false) {
+        features.push("default".to_string());
+    }
+    features
+}
+
+/// Returns the closure of the `requested` features of the package whose manifest is `manifest`.
+///
+/// `default` is ignored if the package does not define it.
+pub(crate) fn enable_features(
+    manifest: &Table,
+    requested: impl IntoIterator<Item = String>,
+) -> Result<EnabledFeatures> {
+    let table = feature_table(manifest);
+    let implicit = implicit_features(manifest);
+    let mut enabled = EnabledFeatures::default();
+    let mut stack: Vec<String> = requested.into_iter().collect();
+    while let Some(feature) = stack.pop() {
+        if let Some(dep) = feature.strip_prefix("dep:") {
+            enabled.deps.insert(dep.to_string());
+            continue;
+        }
+        if let Some((dep, dep_feature)) = feature.split_once('/') {
+            if let Some(dep) = dep.strip_suffix('?') {
+                enabled
+                    .dep_features
+                    .push((dep.to_string(), dep_feature.to_string()));
+                continue;
+            }
+            if implicit.iter().any(|f| f == dep) {
+                stack.push(dep.to_string());
+            } else {
+                enabled.deps.insert(dep.to_string());
+            }
+            enabled
+                .dep_features
+                .push((dep.to_string(), dep_feature.to_string()));
+            continue;
+        }
+        if enabled.features.contains(&feature) {
+            continue;
+        }
+        if let Some(values) = table.get(&feature) {
+            stack.extend(
+                values
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|v| v.as_str().map(str::to_string)),
+            );
+        } else if implicit.contains(&feature) {
+            enabled.deps.insert(feature.clone());
+        } else if feature == "default" {
+            continue;
+        } else {
+            let package = manifest
+                .get("package")
+                .and_then(|p| p.get("name"))
+                .and_then(|n| n.as_str())
+                .unwrap_or_default();
+            return Err(anyhow!("`{feature}` is not a feature of `{package}`").into());
+        }
+        enabled.features.insert(feature);
+    }
+    Ok(enabled)
+}
+
+fn feature_table(manifest: &Table) -> Table {
+    manifest
+        .get("features")
+        .and_then(|f| f.as_table())
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Returns the optional dependencies that have features of the same names,
+/// which are those not referred to as `dep:name` in the feature table.
+fn implicit_features(manifest: &Table) -> Vec<String> {
+    let table = feature_table(manifest);
+    let explicit: Vec<&str> = table
+        .values()
+        .filter_map(|v| v.as_array())
+        .flatten()
+        .filter_map(|v| v.as_str()?.strip_prefix("dep:"))
+        .collect();
+    dependency_entries(manifest)
+        .into_iter()
+        .filter(|(_, value)| value.get("optional").and_then(|o| o.as_bool()) == Some(true))
+        .map(|(key, _)| key)
+        .filter(|key| !explicit.contains(&key.as_str()))
+        .collect()
+}
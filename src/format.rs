@@ -0,0 +1,92 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::anyhow;
+
+use crate::{
+    manifest::{edition, find_manifest, read_manifest},
+    Result,
+};
+
+/// The tool used to format the expanded source.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum FormatStyle {
+    /// Run the `rustfmt` command.
+    Rustfmt,
+    /// Format in-process with `prettyplease`. Comments other than doc comments are removed.
+    Prettyplease,
+}
+
+/// Formats Rust source code.
+#[derive(Clone, Debug)]
+pub struct Formatter {
+    style: FormatStyle,
+    edition: String,
+    config_path: Option<PathBuf>,
+}
+impl Formatter {
+    /// Creates a formatter for the crate whose root file is `path`.
+    ///
+    /// The edition is taken from the nearest `Cargo.toml`, including one inherited from the workspace,
+    /// and `rustfmt` uses the nearest `rustfmt.toml` or `.rustfmt.toml`.
+    pub fn new(style: FormatStyle, path: &Path) -> Result<Self> {
+        let edition = match find_manifest(path) {
+            Some(manifest) => edition(&read_manifest(&manifest)?, &manifest)?
+                .unwrap_or_else(|| "2021".to_string()),
+            None => "2021".to_string(),
+        };
+        let config_path = find_rustfmt_config(path);
+        Ok(Self {
+            style,
+            edition,
+            config_path,
+        })
+    }
+
+    pub fn format(&self, text: &str) -> Result<String> {
+        match self.style {
+            FormatStyle::Rustfmt => self.rustfmt(text),
+            FormatStyle::Prettyplease => Ok(prettyplease::unparse(&syn::parse_file(text)?)),
+        }
+    }
+
+    fn rustfmt(&self, text: &str) -> Result<String> {
+        let mut command = Command::new("rustfmt");
+        command.args(["--emit", "stdout", "--edition", &self.edition]);
+        if let Some(config_path) = &self.config_path {
+            command.arg("--config-path").arg(config_path);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("Could not run `rustfmt` ({e})"))?;
+        child.stdin.take().unwrap().write_all(text.as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`rustfmt` failed\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+}
+
+fn find_rustfmt_config(path: &Path) -> Option<PathBuf> {
+    let path = path.canonicalize().ok()?;
+    for dir in path.ancestors().skip(1) {
+        for name in ["rustfmt.toml", ".rustfmt.toml"] {
+            let config = dir.join(name);
+            if config.is_file() {
+                return Some(config);
+            }
+        }
+    }
+    None
+}
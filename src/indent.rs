@@ -0,0 +1,144 @@
+use std::ops::Range;
+
+/// Indents each line of `text` with `indent`.
+///
+/// Blank lines and lines that start inside a string literal or a block comment are left unchanged,
+/// so that the contents of literals and comments are not altered.
+pub(crate) fn indent_lines(text: &str, indent: &str) -> String {
+    let ranges = literal_ranges(text);
+    let mut ranges = ranges.iter().peekable();
+    let mut s = String::with_capacity(text.len());
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        while ranges.next_if(|r| r.end <= offset).is_some() {}
+        let in_literal = ranges.peek().is_some_and(|r| r.start < offset);
+        if !in_literal && !line.trim().is_empty() {
+            s.push_str(indent);
+        }
+        s.push_str(line);
+        offset += line.len();
+    }
+    s
+}
+
+/// Returns the ranges of string literals, raw string literals and block comments in `s`.
+fn literal_ranges(s: &str) -> Vec<Range<usize>> {
+    let b = s.as_bytes();
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        let start = i;
+        match b[i] {
+            b'/' if b.get(i + 1) == Some(&b'/') => {
+                i = s[i..].find('\n').map_or(b.len(), |n| i + n);
+            }
+            b'/' if b.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                let mut depth = 1;
+                while i < b.len() && depth > 0 {
+                    if b[i..].starts_with(b"/*") {
+                        depth += 1;
+                        i += 2;
+                    } else if b[i..].starts_with(b"*/") {
+                        depth -= 1;
+                        i += 2;
+                    } else {
+                        i += 1;
+                    }
+                }
+                ranges.push(start..i);
+            }
+            b'"' => {
+                i += 1;
+                while i < b.len() {
+                    match b[i] {
+                        b'\\' => i += 2,
+                        b'"' => break,
+                        _ => i += 1,
+                    }
+                }
+                i = (i + 1).min(b.len());
+                ranges.push(start..i);
+            }
+            b'\'' => {
+                // Distinguish character literals from lifetimes and labels.
+                i += 1;
+                if b.get(i) == Some(&b'\\') {
+                    i += 2;
+                    while i < b.len() && b[i] != b'\'' {
+                        i += 1;
+                    }
+                    i += 1;
+                } else if let Some(c) = s[i..].chars().next() {
+                    if b.get(i + c.len_utf8()) == Some(&b'\'') {
+                        i += c.len_utf8() + 1;
+                    }
+                }
+            }
+            c if c == b'_' || c.is_ascii_alphabetic() || !c.is_ascii() => {
+                while i < b.len()
+                    && (b[i] == b'_' || b[i].is_ascii_alphanumeric() || !b[i].is_ascii())
+                {
+                    i += 1;
+                }
+                if matches!(&s[start..i], "r" | "br" | "cr") {
+                    let hashes = b[i..].iter().take_while(|&&c| c == b'#').count();
+                    if b.get(i + hashes) == Some(&b'"') {
+                        i += hashes + 1;
+                        let mut end = "\"".to_string();
+                        end.push_str(&"#".repeat(hashes));
+                        i = s[i..].find(&end).map_or(b.len(), |n| i + n + end.len());
+                        ranges.push(start..i);
+                    }
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literals(s: &str) -> Vec<&str> {
+        literal_ranges(s).into_iter().map(|r| &s[r]).collect()
+    }
+
+    #[test]
+    fn strings_and_block_comments() {
+        let s = "let s = \"a\\\"b\"; /* c /* d */ e */ // \"f\"\nx";
+        assert_eq!(literals(s), ["\"a\\\"b\"", "/* c /* d */ e */"]);
+    }
+
+    #[test]
+    fn raw_strings() {
+        let s = "r#\"a \"b\" c\"#; br\"d\"; cr##\"e\"#\"##; r; rx\"f\"";
+        assert_eq!(
+            literals(s),
+            ["r#\"a \"b\" c\"#", "br\"d\"", "cr##\"e\"#\"##", "\"f\""]
+        );
+    }
+
+    #[test]
+    fn char_literals_and_lifetimes() {
+        let s = "fn f<'a>(x: &'a str) -> [char; 3] { ['\"', '\\'', 'あ'] } 'l: loop {}";
+        assert_eq!(literals(s), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn unterminated_literals() {
+        assert_eq!(literals("\"abc"), ["\"abc"]);
+        assert_eq!(literals("/* abc"), ["/* abc"]);
+    }
+
+    #[test]
+    fn indent_skips_literal_lines() {
+        let text = "fn f() {\n    let s = \"a\nb\";\n\n    /* c\nd */\n}\n";
+        assert_eq!(
+            indent_lines(text, "  "),
+            "  fn f() {\n      let s = \"a\nb\";\n\n      /* c\nd */\n  }\n"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use core::str;
 use std::{
+    collections::{HashMap, HashSet},
     fs, io,
     ops::Range,
     path::{Path, PathBuf},
@@ -8,11 +9,39 @@ use std::{
 
 use annotate_snippets::{Level, Renderer, Snippet};
 use anyhow::anyhow;
-use proc_macro2::{Span, TokenStream};
+use proc_macro2::{Span, TokenStream, TokenTree};
+use quote::ToTokens;
 use syn::{
-    parse2, spanned::Spanned, visit::Visit, Attribute, Expr, ExprLit, File, ItemMod, Lit, Meta,
+    ext::IdentExt, parse2, punctuated::Punctuated, spanned::Spanned, visit::Visit, Arm, AttrStyle,
+    Attribute, Block, Expr, ExprLit, ExprMethodCall, Field, FieldPat, FieldValue, File, FnArg,
+    ForeignItem, GenericParam, Ident, ImplItem, ImplItemFn, Item, ItemFn, ItemImpl, ItemMacro,
+    ItemMod, Lit, Macro, Meta, PathSegment, Signature, Stmt, Token, TraitItem, TraitItemFn,
+    TypeImplTrait, UseTree, Variant, Visibility,
 };
 
+mod bundle;
+mod cfg;
+mod deps;
+mod extern_crate;
+mod extern_refs;
+mod features;
+mod format;
+mod indent;
+mod manifest;
+mod orphans;
+mod rewrite;
+mod tree;
+
+pub use cfg::CfgSet;
+use cfg::{expand_cfg_if, parse_cfg_if, select_cfg_if_branch};
+pub use deps::ModuleGraph;
+use extern_crate::HoistedExternCrate;
+use features::EnabledFeatures;
+pub use features::FeatureSelection;
+pub use format::{FormatStyle, Formatter};
+use rewrite::{Edit, ExternCrate, Rewrite};
+pub use tree::{Module, Resolution};
+
 pub struct Source {
     path: PathBuf,
     text: String,
@@ -22,27 +51,61 @@ pub struct ExpandError {
     e: anyhow::Error,
     span: Option<Range<usize>>,
     source: Option<Source>,
+    footers: Vec<(Level, String)>,
 }
 impl ExpandError {
     fn new(span: Option<Span>, e: impl Into<anyhow::Error>) -> Self {
         let e = e.into();
         let span = span.map(|s| s.byte_range());
         let source = None;
-        Self { e, span, source }
+        let footers = Vec::new();
+        Self {
+            e,
+            span,
+            source,
+            footers,
+        }
+    }
+    /// Adds a note that the file where the error occurred is included by `m` in `path`.
+    fn included_from(self, path: &Path, m: &ItemMod) -> Self {
+        let line = m.ident.span().start().line;
+        self.with_note(format!("included from {}:{line}", path.display()))
+    }
+    fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.footers.push((Level::Note, note.into()));
+        self
+    }
+    fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.footers.push((Level::Help, help.into()));
+        self
+    }
+    fn with_source(mut self, path: &Path, text: &str) -> Self {
+        if self.source.is_none() {
+            let path = path.to_path_buf();
+            let text = text.to_string();
+            self.source = Some(Source { path, text });
+        }
+        self
     }
     pub fn show(&self) {
+        self.show_as(Level::Error);
+    }
+    fn show_as(&self, level: Level) {
         let title = self.e.to_string();
         let path;
-        let mut m = Level::Error.title(&title);
+        let mut m = level.title(&title);
         if let (Some(source), Some(span)) = (&self.source, self.span.clone()) {
             path = source.path.to_string_lossy();
             m = m.snippet(
                 Snippet::source(&source.text)
                     .fold(true)
                     .origin(&path)
-                    .annotation(Level::Error.span(span)),
+                    .annotation(level.span(span)),
             );
         }
+        for (level, footer) in &self.footers {
+            m = m.footer(level.title(footer));
+        }
         let renderer = Renderer::styled();
         eprintln!("{}", renderer.render(m));
     }
@@ -57,52 +120,732 @@ impl<E: Into<anyhow::Error>> From<E> for ExpandError {
 }
 type Result<T> = std::result::Result<T, ExpandError>;
 
+pub struct ExpandWarning(ExpandError);
+
+impl ExpandWarning {
+    pub fn show(&self) {
+        self.0.show_as(Level::Warning);
+    }
+}
+
 fn with_path<T>(r: io::Result<T>, path: &Path) -> Result<T> {
     r.map_err(|e| anyhow!("Could not read file : `{}` ({e})", path.display()).into())
 }
 fn with_source<T>(r: Result<T>, path: &Path, text: &str) -> Result<T> {
-    r.map_err(|mut e| {
-        if e.source.is_none() {
-            let path = path.to_path_buf();
-            let text = text.to_string();
-            e.source = Some(Source { path, text });
-        }
-        e
-    })
+    r.map_err(|e| e.with_source(path, text))
 }
 
+/// Expands the module tree of the file at `path`, whose modules must be in `root`.
+///
+/// `is_root` is true if the file is a crate root, which owns its directory.
+#[deprecated(note = "use `Expander::expand` instead")]
 pub fn expand_from_path(root: &Path, path: &Path, is_root: bool) -> Result<String> {
-    if path.canonicalize()?.strip_prefix(root).is_err() {
-        return Err(ExpandError::new(
-            None,
-            anyhow!("path is out of root directory : `{}`", path.display()),
-        ));
+    let kind = if is_root {
+        FileKind::CrateRoot
+    } else {
+        FileKind::Module
+    };
+    Expander::new().expand_from_path(root, path, kind)
+}
+
+/// Expands the module tree of a crate into a single file.
+#[derive(Default)]
+pub struct Expander {
+    /// Hoisted crate-level attributes as pairs of their tokens (for deduplication) and source text.
+    crate_attrs: Vec<(String, String)>,
+    extern_crates: Vec<HoistedExternCrate>,
+    /// Names in the type namespace defined at the top level of the crate root.
+    root_names: Vec<String>,
+    files: Vec<PathBuf>,
+    /// Texts used instead of the file contents, keyed by absolute paths.
+    virtual_files: HashMap<PathBuf, String>,
+    inlined_formatter: Option<Formatter>,
+    indent: Option<String>,
+    cfg: Option<CfgSet>,
+    features: Option<FeatureSelection>,
+    /// The configuration used to evaluate `cfg` predicates in the crate being expanded,
+    /// which includes its enabled features.
+    crate_cfg: Option<CfgSet>,
+    /// The keys of the `cfg` predicates that could not be decided, which are reported once.
+    undecided_cfg_keys: Vec<String>,
+    /// Names of the dependencies bundled into the crate.
+    bundle: Vec<String>,
+    vendor_dir: Option<PathBuf>,
+    /// The edition of the crate, which is taken from `Cargo.toml` if `None`.
+    edition: Option<String>,
+    /// Names of the external crates that the expanded crate may refer to. `None` disables the check.
+    allowed_externs: Option<Vec<String>>,
+    /// Whether the bodies of functions are replaced by `unimplemented!()`.
+    signatures_only: bool,
+    /// Whether private items are removed along with the bodies of functions.
+    public_only: bool,
+    /// The rewrite applied to the paths in the crate being expanded.
+    rewrite: Rewrite,
+    /// Names of the `#[macro_export]` macros found since the last bundled crate was expanded.
+    exported_macros: Vec<String>,
+    warnings: Vec<ExpandWarning>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FileKind {
+    /// The root file of the crate.
+    CrateRoot,
+    /// An additional input merged into the crate, which owns its directory like `mod.rs`.
+    MergedRoot,
+    /// A file loaded by `mod name;`.
+    Module,
+    /// A file loaded by `mod name;` with `#[path]`, which owns its directory like `mod.rs`.
+    PathModule,
+}
+impl FileKind {
+    /// Returns the kind of the file loaded by `m`.
+    fn of_mod(m: &ItemMod) -> Self {
+        if path_from_attrs(&m.attrs).is_some() {
+            FileKind::PathModule
+        } else {
+            FileKind::Module
+        }
+    }
+    fn owns_dir(self) -> bool {
+        self != FileKind::Module
+    }
+}
+impl Expander {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses `text` as the content of the file at `path` instead of reading it.
+    ///
+    /// The file does not need to exist, but its directory does.
+    pub fn add_virtual_file(&mut self, path: &Path, text: String) -> Result<()> {
+        let path = absolute_path(path)?;
+        self.virtual_files.insert(path, text);
+        Ok(())
+    }
+
+    /// Formats each inlined module with `formatter`, leaving the rest of the root file untouched.
+    pub fn set_inlined_formatter(&mut self, formatter: Option<Formatter>) {
+        self.inlined_formatter = formatter;
+    }
+
+    /// Indents the lines of each inlined module by `indent` per nesting level.
+    pub fn set_indent(&mut self, indent: Option<String>) {
+        self.indent = indent;
+    }
+
+    /// Evaluates `cfg` predicates with `cfg`.
+    ///
+    /// Items, fields, variants, match arms, statements and expressions whose `#[cfg]` is false are removed along with
+    /// their modules, and so are modules whose files start with a false `#![cfg]`. `#[cfg]` attributes that are true
+    /// are removed. `#[cfg_attr]` is replaced by its attributes if the predicate is true, and removed otherwise.
+    /// `cfg_if!` invocations whose conditions can be decided are replaced by the selected branch. Predicates on keys
+    /// not in `cfg` are kept with a warning.
+    pub fn set_cfg(&mut self, cfg: Option<CfgSet>) {
+        self.cfg = cfg;
+    }
+
+    /// Evaluates `cfg(feature = "...")` with the features of the crate enabled by `features`,
+    /// which are resolved with `[features]` in `Cargo.toml`.
+    ///
+    /// The features of bundled dependencies are always evaluated, using the features requested by their dependents.
+    pub fn set_features(&mut self, features: Option<FeatureSelection>) {
+        self.features = features;
+    }
+
+    /// Bundles the dependencies `names` of the crate as its modules.
+    ///
+    /// The sources of registry dependencies are looked up in `vendor_dir` (a directory created by `cargo vendor`)
+    /// if specified, or in the registry cache of Cargo otherwise.
+    pub fn set_bundle(&mut self, names: Vec<String>, vendor_dir: Option<PathBuf>) {
+        self.bundle = names;
+        self.vendor_dir = vendor_dir;
+    }
+
+    /// Uses `edition` as the edition of the crate instead of the one in `Cargo.toml`.
+    ///
+    /// Paths in 2015-edition crates, including bundled ones, are rewritten into the 2018 form.
+    pub fn set_edition(&mut self, edition: Option<String>) {
+        self.edition = edition;
+    }
+
+    /// Warns about references to external crates other than `allowed` and the bundled ones,
+    /// which may not be available where the expanded crate is compiled.
+    pub fn set_allowed_externs(&mut self, allowed: Option<Vec<String>>) {
+        self.allowed_externs = allowed;
+    }
+
+    /// Replaces the bodies of functions with `{ unimplemented!() }`, which leaves a skeleton of the crate with
+    /// its signatures and doc comments. The bodies of `const fn` and functions returning `impl Trait` are kept.
+    ///
+    /// If `public_only` is true, private functions, constants and statics are also removed, along with the private
+    /// methods and associated constants of inherent impls. Those still named by the kept signatures, bodies and
+    /// initializers in the same file are kept, so the skeleton still type-checks.
+    /// Items with restricted visibility such as `pub(crate)` are kept.
+    pub fn set_signatures_only(&mut self, signatures_only: bool, public_only: bool) {
+        self.signatures_only = signatures_only;
+        self.public_only = signatures_only && public_only;
+    }
+
+    /// Expands the crate whose root file is `path`.
+    pub fn expand(&mut self, path: &Path) -> Result<String> {
+        self.crate_attrs.clear();
+        self.extern_crates.clear();
+        self.undecided_cfg_keys.clear();
+        let bundled = self.prepare_crate(path)?;
+        let mut text = self.expand_crate_root(path)?;
+        self.push_bundled(&mut text, path, bundled)?;
+        Ok(text)
+    }
+    fn expand_crate_root(&mut self, path: &Path) -> Result<String> {
+        let root = absolute_path(path)?.parent().unwrap().to_path_buf();
+        self.expand_from_path(&root, path, FileKind::CrateRoot)
+    }
+
+    /// Returns the edition of the crate whose root file is `path`.
+    ///
+    /// Crates outside packages, and those whose edition cannot be determined, are assumed to be written in a recent
+    /// edition.
+    fn root_edition(&self, path: &Path) -> Result<String> {
+        if let Some(edition) = &self.edition {
+            return Ok(edition.clone());
+        }
+        if let Some(manifest_path) = manifest::find_manifest(path) {
+            let manifest = manifest::read_manifest(&manifest_path)?;
+            if let Some(edition) = manifest::edition(&manifest, &manifest_path)? {
+                return Ok(edition);
+            }
+        }
+        Ok("2021".to_string())
+    }
+
+    /// Expands the bundled dependencies of the crate whose root file is `path`, and sets up the rewrite of the
+    /// paths and the configuration used to expand the crate itself.
+    fn prepare_crate(&mut self, path: &Path) -> Result<Vec<(String, String)>> {
+        let root_features = self.root_features(path)?;
+        let mut bundled = Vec::new();
+        let mut externs = HashMap::new();
+        if !self.bundle.is_empty() {
+            let root = root_features.as_ref().unwrap();
+            let bundle = bundle::resolve(
+                path,
+                &self.bundle,
+                self.vendor_dir.as_deref(),
+                self.cfg.as_ref(),
+                root,
+            )?;
+            for (package, target) in &bundle.undecided_targets {
+                self.warnings.push(ExpandWarning(
+                    ExpandError::new(
+                        None,
+                        anyhow!("dependencies of `{package}` for `{target}` were not bundled because the platform cannot be decided"),
+                    )
+                    .with_help("specify the platform with `--cfg`, such as `--cfg unix` or `--cfg windows`"),
+                ));
+            }
+            let mut macros = Vec::new();
+            for c in &bundle.crates {
+                self.rewrite = Rewrite {
+                    crate_path: Some(format!("crate::{}", c.module)),
+                    externs: extern_crates(&bundle, &c.deps, &macros),
+                    edition_2015: c.edition == "2015",
+                    ..Rewrite::default()
+                };
+                self.crate_cfg = Some(self.cfg_with_features(&c.features));
+                self.exported_macros.clear();
+                let root = absolute_path(&c.lib)?.parent().unwrap().to_path_buf();
+                let text = self.expand_from_path(&root, &c.lib, FileKind::MergedRoot)?;
+                macros.push(std::mem::take(&mut self.exported_macros));
+                bundled.push((c.module.clone(), text));
+            }
+            externs = extern_crates(&bundle, &bundle.deps, &macros);
+        }
+        self.rewrite = Rewrite {
+            externs,
+            edition_2015: self.root_edition(path)? == "2015",
+            // Merged files are expanded before the crate root, whose `extern crate` items replace these.
+            root_externs: extern_crate_names(&[]),
+            ..Rewrite::default()
+        };
+        self.crate_cfg = match root_features {
+            Some(enabled) => Some(self.cfg_with_features(&enabled.features)),
+            None => self.cfg.clone(),
+        };
+        Ok(bundled)
+    }
+    /// Returns the enabled features of the crate whose root file is `path`,
+    /// or `None` if features are not evaluated.
+    ///
+    /// Features are evaluated if they are selected or dependencies are bundled.
+    fn root_features(&self, path: &Path) -> Result<Option<EnabledFeatures>> {
+        if self.features.is_none() && self.bundle.is_empty() {
+            return Ok(None);
+        }
+        let selection = self.features.clone().unwrap_or_default();
+        let Some(manifest) = manifest::find_manifest(path) else {
+            let features = selection.features.into_iter().collect();
+            return Ok(Some(EnabledFeatures {
+                features,
+                ..EnabledFeatures::default()
+            }));
+        };
+        let manifest = manifest::read_manifest(&manifest)?;
+        Ok(Some(features::select_features(&manifest, &selection)?))
+    }
+    /// Returns the configuration set by [`Expander::set_cfg`] with `features` enabled.
+    fn cfg_with_features<'a>(&self, features: impl IntoIterator<Item = &'a String>) -> CfgSet {
+        let mut cfg = self.cfg.clone().unwrap_or_default();
+        cfg.declare("feature");
+        for feature in features {
+            cfg.insert("feature", Some(feature));
+        }
+        cfg
+    }
+    /// Appends the bundled dependencies to `text`, the expanded crate whose root file is `path`.
+    fn push_bundled(
+        &mut self,
+        text: &mut String,
+        path: &Path,
+        bundled: Vec<(String, String)>,
+    ) -> Result<()> {
+        self.rewrite = Rewrite::default();
+        for (name, t) in bundled {
+            if self.root_names.contains(&name) {
+                return Err(ExpandError::new(
+                    None,
+                    anyhow!(
+                        "dependency `{name}` cannot be bundled because `{}` already defines `{name}`",
+                        path.display()
+                    ),
+                ));
+            }
+            self.push_module_block(text, &name, &t)?;
+        }
+        Ok(())
+    }
+
+    /// Expands the crate whose root file is `paths[0]`, adding the other files as its modules.
+    ///
+    /// Each additional file becomes `mod name { ... }` named after its file stem.
+    pub fn expand_merged(&mut self, paths: &[PathBuf]) -> Result<String> {
+        let Some((path, others)) = paths.split_first() else {
+            return Ok(String::new());
+        };
+        self.crate_attrs.clear();
+        self.extern_crates.clear();
+        self.undecided_cfg_keys.clear();
+        let bundled = self.prepare_crate(path)?;
+        let mut mods: Vec<(String, &PathBuf)> = Vec::new();
+        for other in others {
+            let mut name = other
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if syn::parse_str::<Ident>(&name).is_err() {
+                // Keywords such as `type` are used as raw identifiers.
+                name = format!("r#{name}");
+            }
+            if syn::parse_str::<Ident>(&name).is_err() {
+                return Err(ExpandError::new(
+                    None,
+                    anyhow!(
+                        "`{name}` cannot be used as a module name : `{}`",
+                        other.display()
+                    ),
+                ));
+            }
+            if let Some((_, prev)) = mods.iter().find(|(n, _)| n == &name) {
+                return Err(ExpandError::new(
+                    None,
+                    anyhow!(
+                        "module `{name}` is defined by both `{}` and `{}`",
+                        prev.display(),
+                        other.display()
+                    ),
+                ));
+            }
+            mods.push((name, other));
+        }
+        let mut texts = Vec::new();
+        for (_, other) in &mods {
+            let root = absolute_path(other)?.parent().unwrap().to_path_buf();
+            texts.push(self.expand_from_path(&root, other, FileKind::MergedRoot)?);
+        }
+        let mut text = self.expand_crate_root(path)?;
+        for ((name, other), t) in mods.iter().zip(texts) {
+            if self.root_names.contains(name) {
+                return Err(ExpandError::new(
+                    None,
+                    anyhow!(
+                        "`{}` cannot be merged as module `{name}` because `{}` already defines `{name}`",
+                        other.display(),
+                        path.display()
+                    ),
+                ));
+            }
+            self.push_module_block(&mut text, name, &t)?;
+        }
+        self.push_bundled(&mut text, path, bundled)?;
+        Ok(text)
+    }
+
+    /// Appends `mod name { ... }` containing `body` to `text`.
+    fn push_module_block(&self, text: &mut String, name: &str, body: &str) -> Result<()> {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        let block = format!("mod {name} {{\n{}}}\n", self.indent_module(body));
+        match &self.inlined_formatter {
+            Some(f) => text.push_str(&f.format(&block)?),
+            None => text.push_str(&block),
+        }
+        Ok(())
+    }
+
+    /// Returns the files read so far and clears them.
+    pub fn take_files(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.files)
+    }
+
+    /// Returns the warnings reported so far and clears them.
+    pub fn take_warnings(&mut self) -> Vec<ExpandWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn expand_from_path(&mut self, root: &Path, path: &Path, kind: FileKind) -> Result<String> {
+        Ok(self.expand_module(root, path, kind)?.unwrap_or_default())
+    }
+    /// Expands the file at `path`, or returns `None` if it is a module removed by its `#![cfg]`.
+    fn expand_module(
+        &mut self,
+        root: &Path,
+        path: &Path,
+        kind: FileKind,
+    ) -> Result<Option<String>> {
+        let s = self.read_file(root, path)?;
+        with_source(self.expand_from_text(root, path, kind, &s), path, &s)
+    }
+    fn read_file(&mut self, root: &Path, path: &Path) -> Result<String> {
+        let abs_path = absolute_path(path)?;
+        if abs_path.strip_prefix(root).is_err() {
+            return Err(ExpandError::new(
+                None,
+                anyhow!("path is out of root directory : `{}`", path.display()),
+            ));
+        }
+
+        self.files.push(path.to_path_buf());
+        let s = match self.virtual_files.get(&abs_path) {
+            Some(s) => s.clone(),
+            None => with_path(fs::read_to_string(path), path)?,
+        };
+        Ok(strip_bom(&s).to_string())
+    }
+    fn indent_module(&self, text: &str) -> String {
+        match &self.indent {
+            Some(indent) => indent::indent_lines(text, indent),
+            None => text.to_string(),
+        }
+    }
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file() || absolute_path(path).is_ok_and(|p| self.virtual_files.contains_key(&p))
+    }
+    fn expand_from_text(
+        &mut self,
+        root: &Path,
+        path: &Path,
+        kind: FileKind,
+        s: &str,
+    ) -> Result<Option<String>> {
+        let mut text = String::new();
+        let mut start = 0;
+        let shebang = shebang_end(s);
+        if let Some(end) = shebang {
+            if kind == FileKind::CrateRoot {
+                text.push_str(&s[..end]);
+                start = end;
+            } else {
+                start = s[end..].strip_prefix('\n').map_or(end, |_| end + 1);
+            }
+        }
+        let file = parse_file(s, shebang)?;
+        if matches!(kind, FileKind::Module | FileKind::PathModule) {
+            if let Some(cfg) = &self.crate_cfg {
+                if cfg.eval_attrs(s, &file.attrs, |_| true).removed {
+                    return Ok(None);
+                }
+            }
+        }
+        let is_library_root = kind == FileKind::MergedRoot && self.rewrite.crate_path.is_some();
+        if kind == FileKind::CrateRoot || is_library_root {
+            self.rewrite.root_externs = extern_crate_names(&file.items);
+        }
+        let mut edits = self.convert_extern_crates(path, kind, s, &file);
+        if !self.rewrite.is_empty() {
+            edits.extend(rewrite::rewrite_edits(
+                &file,
+                &self.rewrite,
+                kind == FileKind::CrateRoot,
+            ));
+            edits.sort_by_key(|(r, _)| r.start);
+        }
+        self.exported_macros.extend(rewrite::exported_macros(&file));
+        if let Some(allowed) = &self.allowed_externs {
+            let refs = extern_refs::external_crate_refs(&file, self.rewrite.edition_2015, |name| {
+                allowed.iter().any(|a| a == name) || self.rewrite.externs.contains_key(name)
+            });
+            for (span, name) in refs {
+                self.warnings.push(ExpandWarning(
+                    ExpandError::new(
+                        Some(span),
+                        anyhow!("reference to external crate `{name}` that is not allowed"),
+                    )
+                    .with_source(path, s)
+                    .with_help("bundle it with `--bundle`, or allow it with `--allow-extern`"),
+                ));
+            }
+        }
+        let mut b = PartsBuilder::new(s, self.crate_cfg.as_ref(), start);
+        b.signatures_only = self.signatures_only;
+        b.public_only = self.public_only;
+        if self.public_only {
+            b.used = used_names(&file);
+        }
+        if kind == FileKind::CrateRoot {
+            self.root_names = type_namespace_names(&file.items);
+        } else {
+            for attr in &file.attrs {
+                match crate_attr_kind(attr) {
+                    Some(CrateAttrKind::Hoist) => {
+                        let key = attr.to_token_stream().to_string();
+                        if !self.crate_attrs.iter().any(|(k, _)| k == &key) {
+                            let text = s[attr.span().byte_range()].to_string();
+                            self.crate_attrs.push((key, text));
+                        }
+                    }
+                    Some(CrateAttrKind::Remove) => {
+                        self.warnings.push(ExpandWarning(
+                            ExpandError::new(
+                                Some(attr.span()),
+                                anyhow!("crate-level attribute in a non-root module was removed"),
+                            )
+                            .with_source(path, s),
+                        ));
+                    }
+                    None => continue,
+                }
+                b.skip(attr.span().byte_range());
+            }
+        }
+        b.visit_file(&file);
+        edits.append(&mut b.edits);
+        edits.sort_by_key(|(r, _)| r.start);
+        for (span, keys) in std::mem::take(&mut b.undecided) {
+            for key in keys {
+                if self.undecided_cfg_keys.contains(&key) {
+                    continue;
+                }
+                self.warnings.push(ExpandWarning(
+                    ExpandError::new(
+                        Some(span),
+                        anyhow!("`cfg` on `{key}` cannot be decided, so it was kept"),
+                    )
+                    .with_source(path, s)
+                    .with_help(format!(
+                        "specify `{key}` with `--cfg` if it is set; other `cfg` on it are not reported"
+                    )),
+                ));
+                self.undecided_cfg_keys.push(key);
+            }
+        }
+        let mut mod_start = 0;
+        let mut attrs_end = 0;
+        // Whether the whitespace at the start of the next text is removed, as after a removed item.
+        let mut trim_start = false;
+        for part in b.finish() {
+            match part {
+                Part::Text(mut r) => {
+                    if std::mem::take(&mut trim_start) {
+                        let t = &s[r.clone()];
+                        r.start += t.len() - t.trim_start().len();
+                    }
+                    rewrite::push_with_edits(&mut text, s, r, &edits)
+                }
+                Part::InnerAttrsEnd => attrs_end = text.len(),
+                Part::ModStart => mod_start = text.len(),
+                Part::Mod(m) => {
+                    let mod_path = path_from_mod(path, kind.owns_dir(), &m, |p| self.is_file(p))?;
+                    let t = self
+                        .expand_module(root, &mod_path, FileKind::of_mod(&m))
+                        .map_err(|e| e.included_from(path, &m))?;
+                    let Some(t) = t else {
+                        text.truncate(mod_start);
+                        trim_start = true;
+                        continue;
+                    };
+                    text.push_str(" {\n");
+                    text.push_str(&self.indent_module(&t));
+                    text.push_str("}\n");
+                    if kind == FileKind::CrateRoot {
+                        if let Some(f) = &self.inlined_formatter {
+                            let block = text.split_off(mod_start);
+                            text.push_str(&f.format(&block)?);
+                        }
+                    }
+                }
+            }
+        }
+        if kind == FileKind::CrateRoot {
+            self.insert_extern_crates(&mut text, &file, attrs_end);
+            let mut attrs = String::new();
+            for (key, attr) in &self.crate_attrs {
+                if !file
+                    .attrs
+                    .iter()
+                    .any(|a| &a.to_token_stream().to_string() == key)
+                {
+                    attrs.push_str(attr);
+                    attrs.push('\n');
+                }
+            }
+            let start = if start == 0 { 0 } else { start + 1 }.min(text.len());
+            text.insert_str(start, &attrs);
+        }
+        Ok(Some(text))
+    }
+}
+
+impl Expander {
+    /// Inserts the hoisted `extern crate` items into `text`, the expanded root file, at `end`,
+    /// the end of its inner attributes in `text`.
+    fn insert_extern_crates(&mut self, text: &mut String, file: &File, end: usize) {
+        let mut items = String::new();
+        for c in &self.extern_crates {
+            if self.root_names.contains(&c.name) {
+                let same = file
+                    .items
+                    .iter()
+                    .any(|item| matches!(item, Item::ExternCrate(i) if i.ident == c.krate));
+                if !same {
+                    self.warnings.push(ExpandWarning(ExpandError::new(
+                        None,
+                        anyhow!(
+                            "`{}` cannot be moved to the crate root because it already defines `{}`",
+                            c.text,
+                            c.name
+                        ),
+                    )));
+                }
+                continue;
+            }
+            items.push_str(&c.text);
+            items.push('\n');
+        }
+        if items.is_empty() {
+            return;
+        }
+        if end == 0 || text[..end].ends_with('\n') {
+            text.insert_str(end, &items);
+        } else {
+            items.pop();
+            text.insert_str(end, &format!("\n{items}"));
+        }
     }
+}
+
+/// Returns the bundled crates `deps` keyed by their names in code, where `macros[i]` are the exported macros of
+/// `bundle.crates[i]`.
+fn extern_crates(
+    bundle: &bundle::Bundle,
+    deps: &[(String, usize)],
+    macros: &[Vec<String>],
+) -> HashMap<String, ExternCrate> {
+    deps.iter()
+        .map(|(name, i)| {
+            let ext = ExternCrate {
+                path: format!("crate::{}", bundle.crates[*i].module),
+                macros: macros[*i].clone(),
+            };
+            (name.clone(), ext)
+        })
+        .collect()
+}
+
+enum CrateAttrKind {
+    /// Attributes such as `#![feature(...)]` that are moved to the crate root.
+    Hoist,
+    /// Attributes such as `#![no_std]` that rustc ignores outside the crate root.
+    Remove,
+}
 
-    let s = with_path(fs::read_to_string(path), path)?;
-    with_source(expand_from_text(root, path, is_root, &s), path, &s)
+const CRATE_LEVEL_ATTRS: &[&str] = &[
+    "crate_name",
+    "crate_type",
+    "no_builtins",
+    "no_core",
+    "no_main",
+    "no_std",
+    "recursion_limit",
+    "type_length_limit",
+    "windows_subsystem",
+];
+
+fn crate_attr_kind(attr: &Attribute) -> Option<CrateAttrKind> {
+    if matches!(attr.style, AttrStyle::Outer) {
+        return None;
+    }
+    crate_meta_kind(&attr.meta)
 }
-fn expand_from_text(root: &Path, path: &Path, is_root: bool, s: &str) -> Result<String> {
-    let tokens = parse_token_stream(s)?;
-    let file: File = parse2(tokens)?;
-    let mut b = PartsBuilder::new();
-    b.visit_file(&file);
-    let mut text = String::new();
-    for part in b.finish(s.len()) {
-        match part {
-            Part::Text(r) => text.push_str(&s[r]),
-            Part::Mod(m) => {
-                text.push_str(" {\n");
-                text.push_str(&expand_from_path(
-                    root,
-                    &path_from_mod(path, is_root, &m)?,
-                    false,
-                )?);
-                text.push_str("}\n");
+fn crate_meta_kind(meta: &Meta) -> Option<CrateAttrKind> {
+    let path = meta.path();
+    if path.is_ident("feature") {
+        return Some(CrateAttrKind::Hoist);
+    }
+    if CRATE_LEVEL_ATTRS.iter().any(|name| path.is_ident(name)) {
+        return Some(CrateAttrKind::Remove);
+    }
+    if path.is_ident("cfg_attr") {
+        let list = meta.require_list().ok()?;
+        let metas = list
+            .parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+            .ok()?;
+        let mut kind = None;
+        for meta in metas.iter().skip(1) {
+            match crate_meta_kind(meta) {
+                Some(CrateAttrKind::Hoist) => {
+                    kind.get_or_insert(CrateAttrKind::Hoist);
+                }
+                Some(CrateAttrKind::Remove) => kind = Some(CrateAttrKind::Remove),
+                None => {}
             }
         }
+        return kind;
+    }
+    None
+}
+
+fn strip_bom(s: &str) -> &str {
+    s.strip_prefix('\u{feff}').unwrap_or(s)
+}
+
+/// Returns the end of the shebang line (excluding the line break), if any.
+///
+/// The shebang is replaced with spaces before parsing so that the spans stay valid for the original text.
+fn shebang_end(s: &str) -> Option<usize> {
+    let rest = s.strip_prefix("#!")?;
+    if rest.trim_start().starts_with('[') {
+        return None;
     }
-    Ok(text)
+    Some(s.find('\n').unwrap_or(s.len()))
+}
+
+/// Parses `s`, ignoring the shebang line that ends at `shebang_end`.
+fn parse_file(s: &str, shebang_end: Option<usize>) -> Result<File> {
+    let tokens = match shebang_end {
+        Some(end) => parse_token_stream(&format!("{}{}", " ".repeat(end), &s[end..]))?,
+        None => parse_token_stream(s)?,
+    };
+    Ok(parse2(tokens)?)
 }
 
 fn parse_token_stream(s: &str) -> syn::Result<TokenStream> {
@@ -112,29 +855,380 @@ fn parse_token_stream(s: &str) -> syn::Result<TokenStream> {
     }
 }
 
-fn path_from_mod(path: &Path, is_root: bool, m: &ItemMod) -> Result<PathBuf> {
+/// Returns the canonical path of `path`, or of its directory if the file does not exist.
+fn absolute_path(path: &Path) -> Result<PathBuf> {
+    if let Ok(p) = path.canonicalize() {
+        return Ok(p);
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("not a file path : `{}`", path.display()))?;
+    Ok(with_path(dir.canonicalize(), dir)?.join(file_name))
+}
+
+fn path_from_mod(
+    path: &Path,
+    owns_dir: bool,
+    m: &ItemMod,
+    is_file: impl Fn(&Path) -> bool,
+) -> Result<PathBuf> {
     match path_from_attrs(&m.attrs) {
         Some(p) => Ok(path.parent().unwrap().join(p)),
         None => {
-            let name = m.ident.to_string();
+            let name = m.ident.unraw().to_string();
+            if !name.is_ascii() {
+                return Err(ExpandError::new(
+                    Some(m.ident.span()),
+                    anyhow!(
+                        "trying to load file for module `{name}` with non-ascii identifier name"
+                    ),
+                )
+                .with_help("consider using the `#[path]` attribute to specify filesystem path"));
+            }
             let file_name = path.file_name().unwrap();
-            let base = if is_root || file_name == "mod.rs" {
+            let base = if owns_dir || file_name == "mod.rs" {
                 path.parent().unwrap().to_path_buf()
             } else {
                 path.with_extension("")
             };
             let p0 = base.join(format!("{name}.rs"));
             let p1 = base.join(format!("{name}/mod.rs"));
-            for p in &[&p0, &p1] {
-                if p.is_file() {
-                    return Ok(p.to_path_buf());
+            match (is_file(&p0), is_file(&p1)) {
+                (true, false) => Ok(p0),
+                (false, true) => Ok(p1),
+                (true, true) => Err(ExpandError::new(
+                    Some(m.span()),
+                    anyhow!(
+                        "file for module `{name}` found at both `{}` and `{}`",
+                        p0.display(),
+                        p1.display()
+                    ),
+                )
+                .with_note(format!("candidate : `{}`", p0.display()))
+                .with_note(format!("candidate : `{}`", p1.display()))
+                .with_help("delete or rename one of them to remove the ambiguity")),
+                (false, false) => {
+                    let mut e = ExpandError::new(
+                        Some(m.span()),
+                        anyhow!("Could not find source file : `{}`", p0.display()),
+                    )
+                    .with_note(format!(
+                        "tried `{}` and `{}`",
+                        p0.display(),
+                        p1.display()
+                    ));
+                    if let Some(p) = similar_module_file(&base, &name) {
+                        e = e.with_help(format!("did you mean `{}`?", p.display()));
+                    }
+                    Err(e)
+                }
+            }
+        }
+    }
+}
+
+/// Returns the module file in `dir` whose module name is most similar to `name`.
+fn similar_module_file(dir: &Path, name: &str) -> Option<PathBuf> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut best: Option<(usize, PathBuf)> = None;
+    let read_dir = if dir.as_os_str().is_empty() {
+        fs::read_dir(".")
+    } else {
+        fs::read_dir(dir)
+    };
+    for entry in read_dir.ok()?.flatten() {
+        let path = dir.join(entry.file_name());
+        let (candidate, p) = if path.is_dir() {
+            let p = path.join("mod.rs");
+            if !p.is_file() {
+                continue;
+            }
+            (entry.file_name(), p)
+        } else if path.extension().is_some_and(|e| e == "rs") {
+            let Some(stem) = path.file_stem() else {
+                continue;
+            };
+            (stem.to_os_string(), path.clone())
+        } else {
+            continue;
+        };
+        let d = edit_distance(name, &candidate.to_string_lossy());
+        if d <= max_distance && best.as_ref().is_none_or(|(best, _)| d < *best) {
+            best = Some((d, p));
+        }
+    }
+    best.map(|(_, p)| p)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == cb {
+                prev
+            } else {
+                prev.min(cur).min(row[j]) + 1
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+fn type_namespace_names(items: &[Item]) -> Vec<String> {
+    let mut names = Vec::new();
+    for item in items {
+        match item {
+            Item::Mod(i) => names.push(i.ident.to_string()),
+            Item::Struct(i) => names.push(i.ident.to_string()),
+            Item::Enum(i) => names.push(i.ident.to_string()),
+            Item::Union(i) => names.push(i.ident.to_string()),
+            Item::Trait(i) => names.push(i.ident.to_string()),
+            Item::TraitAlias(i) => names.push(i.ident.to_string()),
+            Item::Type(i) => names.push(i.ident.to_string()),
+            Item::ExternCrate(i) => match &i.rename {
+                Some((_, rename)) => names.push(rename.to_string()),
+                None => names.push(i.ident.to_string()),
+            },
+            Item::Use(i) => use_tree_names(&i.tree, &mut names),
+            _ => {}
+        }
+    }
+    names
+}
+/// Returns the names bound by the `extern crate` items in `items`, and `std` and `core`.
+fn extern_crate_names(items: &[Item]) -> Vec<String> {
+    let mut names = vec!["std".to_string(), "core".to_string()];
+    for item in items {
+        if let Item::ExternCrate(i) = item {
+            let name = i.rename.as_ref().map_or(&i.ident, |(_, rename)| rename);
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+fn use_tree_names(tree: &UseTree, names: &mut Vec<String>) {
+    use_tree_names_in(tree, None, names);
+}
+/// `parent` is the last segment before `tree`, which `self` in a group such as `use std::io::{self}` refers to.
+fn use_tree_names_in(tree: &UseTree, parent: Option<&Ident>, names: &mut Vec<String>) {
+    match tree {
+        UseTree::Path(p) => use_tree_names_in(&p.tree, Some(&p.ident), names),
+        UseTree::Name(n) if n.ident == "self" => names.extend(parent.map(|p| p.to_string())),
+        UseTree::Name(n) => names.push(n.ident.to_string()),
+        UseTree::Rename(r) => names.push(r.rename.to_string()),
+        UseTree::Glob(_) => {}
+        UseTree::Group(g) => {
+            for tree in &g.items {
+                use_tree_names_in(tree, parent, names);
+            }
+        }
+    }
+}
+
+fn item_attrs(item: &Item) -> &[Attribute] {
+    match item {
+        Item::Const(i) => &i.attrs,
+        Item::Enum(i) => &i.attrs,
+        Item::ExternCrate(i) => &i.attrs,
+        Item::Fn(i) => &i.attrs,
+        Item::ForeignMod(i) => &i.attrs,
+        Item::Impl(i) => &i.attrs,
+        Item::Macro(i) => &i.attrs,
+        Item::Mod(i) => &i.attrs,
+        Item::Static(i) => &i.attrs,
+        Item::Struct(i) => &i.attrs,
+        Item::Trait(i) => &i.attrs,
+        Item::TraitAlias(i) => &i.attrs,
+        Item::Type(i) => &i.attrs,
+        Item::Union(i) => &i.attrs,
+        Item::Use(i) => &i.attrs,
+        _ => &[],
+    }
+}
+
+/// Returns whether `item` is removed as a private item by [`Expander::set_signatures_only`].
+///
+/// Only functions, constants and statics are removed, since private types and traits may appear in public
+/// signatures. Those named in `used` are kept.
+fn is_private_item(item: &Item, used: &HashSet<String>) -> bool {
+    let (vis, ident) = match item {
+        Item::Const(i) => (&i.vis, &i.ident),
+        Item::Fn(i) => (&i.vis, &i.sig.ident),
+        Item::Static(i) => (&i.vis, &i.ident),
+        _ => return false,
+    };
+    matches!(vis, Visibility::Inherited) && !used.contains(&ident.to_string())
+}
+
+/// Returns the names used by the code that [`Expander::set_signatures_only`] keeps in `file`, such as constants in
+/// array lengths and functions called in the bodies of `const fn`.
+fn used_names(file: &File) -> HashSet<String> {
+    struct V(HashSet<String>);
+    impl V {
+        fn visit_tokens(&mut self, tokens: TokenStream) {
+            for tt in tokens {
+                match tt {
+                    TokenTree::Ident(ident) => {
+                        self.0.insert(ident.to_string());
+                    }
+                    TokenTree::Group(g) => self.visit_tokens(g.stream()),
+                    _ => {}
                 }
             }
-            Err(ExpandError::new(
-                Some(m.span()),
-                anyhow!("Could not find source file : `{}`", p0.display()),
-            ))
         }
+        fn visit_fn(&mut self, sig: &Signature, block: Option<&Block>) {
+            self.visit_signature(sig);
+            if let Some(block) = block.filter(|_| keeps_body(sig)) {
+                self.visit_block(block);
+            }
+        }
+    }
+    impl<'ast> Visit<'ast> for V {
+        fn visit_path_segment(&mut self, i: &'ast PathSegment) {
+            self.0.insert(i.ident.to_string());
+            syn::visit::visit_path_segment(self, i);
+        }
+        fn visit_expr_method_call(&mut self, i: &'ast ExprMethodCall) {
+            self.0.insert(i.method.to_string());
+            syn::visit::visit_expr_method_call(self, i);
+        }
+        fn visit_macro(&mut self, i: &'ast Macro) {
+            self.visit_path(&i.path);
+            self.visit_tokens(i.tokens.clone());
+        }
+        fn visit_item_fn(&mut self, i: &'ast ItemFn) {
+            self.visit_fn(&i.sig, Some(&i.block));
+        }
+        fn visit_impl_item_fn(&mut self, i: &'ast ImplItemFn) {
+            self.visit_fn(&i.sig, Some(&i.block));
+        }
+        fn visit_trait_item_fn(&mut self, i: &'ast TraitItemFn) {
+            self.visit_fn(&i.sig, i.default.as_ref());
+        }
+    }
+    let mut v = V(HashSet::new());
+    v.visit_file(file);
+    v.0
+}
+
+/// Extends `range` to the whole lines if nothing else is on them, so that removing it leaves no blank line
+/// and keeps the indentation of the following line.
+fn line_range(text: &str, range: Range<usize>) -> Range<usize> {
+    let before = text[..range.start].trim_end_matches([' ', '\t']);
+    let rest = &text[range.end..];
+    let after = rest.trim_start_matches([' ', '\t']);
+    let end = range.end + (rest.len() - after.len());
+    match after
+        .strip_prefix("\r\n")
+        .or_else(|| after.strip_prefix('\n'))
+    {
+        Some(next) if before.is_empty() || before.ends_with('\n') => {
+            before.len()..text.len() - next.len()
+        }
+        _ => range.start..end,
+    }
+}
+
+/// Returns whether the body of a function is kept by [`Expander::set_signatures_only`].
+///
+/// The bodies of functions returning `impl Trait` are kept since the return types are inferred from them,
+/// and so are those of `const fn` since they may be evaluated in constants.
+fn keeps_body(sig: &Signature) -> bool {
+    sig.constness.is_some() || returns_impl_trait(sig)
+}
+fn returns_impl_trait(sig: &Signature) -> bool {
+    struct V(bool);
+    impl<'ast> Visit<'ast> for V {
+        fn visit_type_impl_trait(&mut self, _: &'ast TypeImplTrait) {
+            self.0 = true;
+        }
+    }
+    let mut v = V(false);
+    v.visit_return_type(&sig.output);
+    v.0
+}
+
+fn impl_item_attrs(item: &ImplItem) -> &[Attribute] {
+    match item {
+        ImplItem::Const(i) => &i.attrs,
+        ImplItem::Fn(i) => &i.attrs,
+        ImplItem::Type(i) => &i.attrs,
+        ImplItem::Macro(i) => &i.attrs,
+        _ => &[],
+    }
+}
+
+fn trait_item_attrs(item: &TraitItem) -> &[Attribute] {
+    match item {
+        TraitItem::Const(i) => &i.attrs,
+        TraitItem::Fn(i) => &i.attrs,
+        TraitItem::Type(i) => &i.attrs,
+        TraitItem::Macro(i) => &i.attrs,
+        _ => &[],
+    }
+}
+
+fn foreign_item_attrs(item: &ForeignItem) -> &[Attribute] {
+    match item {
+        ForeignItem::Fn(i) => &i.attrs,
+        ForeignItem::Static(i) => &i.attrs,
+        ForeignItem::Type(i) => &i.attrs,
+        ForeignItem::Macro(i) => &i.attrs,
+        _ => &[],
+    }
+}
+
+fn expr_attrs(expr: &Expr) -> &[Attribute] {
+    match expr {
+        Expr::Array(e) => &e.attrs,
+        Expr::Assign(e) => &e.attrs,
+        Expr::Async(e) => &e.attrs,
+        Expr::Await(e) => &e.attrs,
+        Expr::Binary(e) => &e.attrs,
+        Expr::Block(e) => &e.attrs,
+        Expr::Break(e) => &e.attrs,
+        Expr::Call(e) => &e.attrs,
+        Expr::Cast(e) => &e.attrs,
+        Expr::Closure(e) => &e.attrs,
+        Expr::Const(e) => &e.attrs,
+        Expr::Continue(e) => &e.attrs,
+        Expr::Field(e) => &e.attrs,
+        Expr::ForLoop(e) => &e.attrs,
+        Expr::Group(e) => &e.attrs,
+        Expr::If(e) => &e.attrs,
+        Expr::Index(e) => &e.attrs,
+        Expr::Infer(e) => &e.attrs,
+        Expr::Let(e) => &e.attrs,
+        Expr::Lit(e) => &e.attrs,
+        Expr::Loop(e) => &e.attrs,
+        Expr::Macro(e) => &e.attrs,
+        Expr::Match(e) => &e.attrs,
+        Expr::MethodCall(e) => &e.attrs,
+        Expr::Paren(e) => &e.attrs,
+        Expr::Path(e) => &e.attrs,
+        Expr::Range(e) => &e.attrs,
+        Expr::RawAddr(e) => &e.attrs,
+        Expr::Reference(e) => &e.attrs,
+        Expr::Repeat(e) => &e.attrs,
+        Expr::Return(e) => &e.attrs,
+        Expr::Struct(e) => &e.attrs,
+        Expr::Try(e) => &e.attrs,
+        Expr::TryBlock(e) => &e.attrs,
+        Expr::Tuple(e) => &e.attrs,
+        Expr::Unary(e) => &e.attrs,
+        Expr::Unsafe(e) => &e.attrs,
+        Expr::While(e) => &e.attrs,
+        Expr::Yield(e) => &e.attrs,
+        _ => &[],
     }
 }
 
@@ -166,34 +1260,334 @@ fn path_from_attr(attr: &Attribute) -> Option<PathBuf> {
 
 enum Part {
     Text(Range<usize>),
+    /// The end of the inner attributes of the file, after which hoisted `extern crate` items are inserted.
+    InnerAttrsEnd,
+    /// The start of a `mod` item including its attributes.
+    ModStart,
     Mod(ItemMod),
 }
 
-struct PartsBuilder {
+struct PartsBuilder<'a> {
+    text: &'a str,
+    cfg: Option<&'a CfgSet>,
     offset: usize,
+    /// The number of inline modules containing the current item.
+    inline_depth: usize,
     parts: Vec<Part>,
+    /// The edits that remove the nodes other than items whose `cfg` is false, and evaluate `cfg` and `cfg_attr`
+    /// attributes.
+    edits: Vec<Edit>,
+    /// The `cfg` predicates that cannot be decided, with the keys that make them undecidable.
+    undecided: Vec<(Span, Vec<String>)>,
+    /// Whether the bodies of functions are replaced by `unimplemented!()`.
+    signatures_only: bool,
+    /// Whether private items are removed.
+    public_only: bool,
+    /// The names used by the kept code, which are not removed as private items.
+    used: HashSet<String>,
 }
-impl PartsBuilder {
-    fn new() -> Self {
+impl<'a> PartsBuilder<'a> {
+    fn new(text: &'a str, cfg: Option<&'a CfgSet>, offset: usize) -> Self {
         Self {
-            offset: 0,
+            text,
+            cfg,
+            offset,
+            inline_depth: 0,
             parts: Vec::new(),
+            edits: Vec::new(),
+            undecided: Vec::new(),
+            signatures_only: false,
+            public_only: false,
+            used: HashSet::new(),
         }
     }
-    fn finish(self, source_len: usize) -> Vec<Part> {
+    fn finish(self) -> Vec<Part> {
         let mut parts = self.parts;
-        parts.push(Part::Text(self.offset..source_len));
+        parts.push(Part::Text(self.offset..self.text.len()));
         parts
     }
+    fn skip(&mut self, span: Range<usize>) {
+        self.parts.push(Part::Text(self.offset..span.start));
+        let rest = &self.text[span.end..];
+        self.offset = span.end + (rest.len() - rest.trim_start().len());
+    }
+    /// Evaluates the `cfg` and `cfg_attr` attributes of a node other than an item, and returns whether it is kept.
+    ///
+    /// A removed node is followed by its separator, such as the comma after a field, which is removed with it.
+    fn eval_cfg(&mut self, attrs: &[Attribute], span: Span) -> bool {
+        let Some(cfg) = self.cfg else {
+            return true;
+        };
+        let eval = cfg.eval_attrs(self.text, attrs, |_| true);
+        self.undecided.extend(eval.undecided);
+        if eval.removed {
+            let range = span.byte_range();
+            let mut end = range.end;
+            if let Some(rest) = self.text[end..].trim_start().strip_prefix(',') {
+                end = self.text.len() - rest.len();
+            }
+            let range = line_range(self.text, range.start..end);
+            self.edits.push((range, String::new()));
+            return false;
+        }
+        self.edits.extend(eval.edits);
+        true
+    }
+    /// Visits the signature of a function, and replaces its body with `unimplemented!()` if `signatures_only` is set
+    /// and [`keeps_body`] is false.
+    fn visit_fn(&mut self, sig: &Signature, block: &Block) {
+        self.visit_signature(sig);
+        if self.signatures_only && !keeps_body(sig) {
+            let body = "{ unimplemented!() }".to_string();
+            self.edits.push((block.span().byte_range(), body));
+        } else {
+            self.visit_block(block);
+        }
+    }
+    /// Pushes a `mod` item loaded from a file, whose `#[path]` may be given by `cfg_attr` as `cfg_attrs`.
+    fn push_mod(&mut self, i: &ItemMod, cfg_attrs: Vec<Attribute>) {
+        let start = i.span().byte_range().start.max(self.offset);
+        self.parts.push(Part::Text(self.offset..start));
+        self.parts.push(Part::ModStart);
+        self.offset = start;
+        for attr in &i.attrs {
+            if path_from_attr(attr).is_some() {
+                self.skip(attr.span().byte_range());
+            }
+        }
+        let end = i.ident.span().byte_range().end;
+        self.parts.push(Part::Text(self.offset..end));
+        let mut m = i.clone();
+        m.attrs.extend(cfg_attrs);
+        self.parts.push(Part::Mod(m));
+        self.offset = i.span().byte_range().end;
+    }
 }
-impl<'ast> Visit<'ast> for PartsBuilder {
+impl<'ast> Visit<'ast> for PartsBuilder<'_> {
+    fn visit_file(&mut self, i: &'ast File) {
+        if let Some(cfg) = self.cfg {
+            // Modules whose `#![cfg]` is false are removed before they are visited, and crate roots are kept.
+            let eval = cfg.eval_attrs(self.text, &i.attrs, |_| true);
+            self.undecided.extend(eval.undecided);
+            if !eval.removed {
+                self.edits.extend(eval.edits);
+            }
+        }
+        // The edits of the inner attributes, which may remove the whitespace after them, end before the marker.
+        let end = i
+            .attrs
+            .iter()
+            .filter(|a| matches!(a.style, AttrStyle::Inner(_)))
+            .map(|a| a.span().byte_range().end)
+            .fold(self.offset, usize::max);
+        let end = self
+            .edits
+            .iter()
+            .filter(|(r, _)| r.start < end)
+            .map(|(r, _)| r.end)
+            .fold(end, usize::max);
+        self.parts.push(Part::Text(self.offset..end));
+        self.parts.push(Part::InnerAttrsEnd);
+        self.offset = end;
+        syn::visit::visit_file(self, i);
+    }
+    fn visit_item(&mut self, i: &'ast Item) {
+        if self.public_only && is_private_item(i, &self.used) {
+            self.skip(i.span().byte_range());
+            return;
+        }
+        // Remove the item if its `cfg` is false, and evaluate its `cfg` and `cfg_attr` attributes otherwise.
+        if let Some(cfg) = self.cfg {
+            let file_mod = match i {
+                Item::Mod(m) if m.content.is_none() && self.inline_depth == 0 => Some(m),
+                _ => None,
+            };
+            // `#[path]` given by `cfg_attr` is used to load the file instead of being kept.
+            let eval = cfg.eval_attrs(self.text, item_attrs(i), |m| {
+                file_mod.is_none() || !m.path().is_ident("path")
+            });
+            self.undecided.extend(eval.undecided);
+            if eval.removed {
+                self.skip(i.span().byte_range());
+                return;
+            }
+            self.edits.extend(eval.edits);
+            if let Some(m) = file_mod {
+                let attrs = eval
+                    .dropped
+                    .into_iter()
+                    .map(|meta| Attribute {
+                        pound_token: Default::default(),
+                        style: AttrStyle::Outer,
+                        bracket_token: Default::default(),
+                        meta,
+                    })
+                    .collect();
+                self.push_mod(m, attrs);
+                return;
+            }
+        }
+        syn::visit::visit_item(self, i);
+    }
     fn visit_item_mod(&mut self, i: &'ast ItemMod) {
         if i.content.is_some() {
+            self.inline_depth += 1;
+            syn::visit::visit_item_mod(self, i);
+            self.inline_depth -= 1;
             return;
         }
-        let end = i.ident.span().byte_range().end;
-        self.parts.push(Part::Text(self.offset..end));
-        self.parts.push(Part::Mod(i.clone()));
-        self.offset = i.span().byte_range().end;
+        // `mod name;` in inline modules is left as is.
+        if self.inline_depth > 0 {
+            return;
+        }
+        self.push_mod(i, Vec::new());
+    }
+    fn visit_item_fn(&mut self, i: &'ast ItemFn) {
+        self.visit_visibility(&i.vis);
+        self.visit_fn(&i.sig, &i.block);
+    }
+    fn visit_impl_item_fn(&mut self, i: &'ast ImplItemFn) {
+        self.visit_visibility(&i.vis);
+        self.visit_fn(&i.sig, &i.block);
+    }
+    fn visit_trait_item_fn(&mut self, i: &'ast TraitItemFn) {
+        match &i.default {
+            Some(block) => self.visit_fn(&i.sig, block),
+            None => self.visit_signature(&i.sig),
+        }
+    }
+    fn visit_item_impl(&mut self, i: &'ast ItemImpl) {
+        if !self.public_only || i.trait_.is_some() {
+            syn::visit::visit_item_impl(self, i);
+            return;
+        }
+        self.visit_generics(&i.generics);
+        self.visit_type(&i.self_ty);
+        for item in &i.items {
+            let private = match item {
+                ImplItem::Const(c) => Some((&c.vis, &c.ident)),
+                ImplItem::Fn(f) => Some((&f.vis, &f.sig.ident)),
+                _ => None,
+            };
+            if private.is_some_and(|(vis, ident)| {
+                matches!(vis, Visibility::Inherited) && !self.used.contains(&ident.to_string())
+            }) {
+                let range = line_range(self.text, item.span().byte_range());
+                self.edits.push((range, String::new()));
+            } else {
+                self.visit_impl_item(item);
+            }
+        }
+    }
+    fn visit_field(&mut self, i: &'ast Field) {
+        if self.eval_cfg(&i.attrs, i.span()) {
+            syn::visit::visit_field(self, i);
+        }
+    }
+    fn visit_variant(&mut self, i: &'ast Variant) {
+        if self.eval_cfg(&i.attrs, i.span()) {
+            syn::visit::visit_variant(self, i);
+        }
+    }
+    fn visit_generic_param(&mut self, i: &'ast GenericParam) {
+        let attrs = match i {
+            GenericParam::Lifetime(p) => &p.attrs,
+            GenericParam::Type(p) => &p.attrs,
+            GenericParam::Const(p) => &p.attrs,
+        };
+        if self.eval_cfg(attrs, i.span()) {
+            syn::visit::visit_generic_param(self, i);
+        }
+    }
+    fn visit_fn_arg(&mut self, i: &'ast FnArg) {
+        let attrs = match i {
+            FnArg::Receiver(r) => &r.attrs,
+            FnArg::Typed(t) => &t.attrs,
+        };
+        if self.eval_cfg(attrs, i.span()) {
+            syn::visit::visit_fn_arg(self, i);
+        }
+    }
+    fn visit_impl_item(&mut self, i: &'ast ImplItem) {
+        if self.eval_cfg(impl_item_attrs(i), i.span()) {
+            syn::visit::visit_impl_item(self, i);
+        }
+    }
+    fn visit_trait_item(&mut self, i: &'ast TraitItem) {
+        if self.eval_cfg(trait_item_attrs(i), i.span()) {
+            syn::visit::visit_trait_item(self, i);
+        }
+    }
+    fn visit_foreign_item(&mut self, i: &'ast ForeignItem) {
+        if self.eval_cfg(foreign_item_attrs(i), i.span()) {
+            syn::visit::visit_foreign_item(self, i);
+        }
+    }
+    fn visit_stmt(&mut self, i: &'ast Stmt) {
+        // The attributes of an expression statement are evaluated with the expression,
+        // but the semicolon is removed with it.
+        let keep = match i {
+            Stmt::Local(l) => self.eval_cfg(&l.attrs, i.span()),
+            Stmt::Macro(m) => self.eval_cfg(&m.attrs, i.span()),
+            Stmt::Expr(e, Some(_)) => match self.cfg {
+                Some(cfg) if cfg.eval_attrs(self.text, expr_attrs(e), |_| true).removed => {
+                    self.eval_cfg(expr_attrs(e), i.span())
+                }
+                _ => true,
+            },
+            Stmt::Expr(_, None) | Stmt::Item(_) => true,
+        };
+        if keep {
+            syn::visit::visit_stmt(self, i);
+        }
+    }
+    fn visit_expr(&mut self, i: &'ast Expr) {
+        if self.eval_cfg(expr_attrs(i), i.span()) {
+            syn::visit::visit_expr(self, i);
+        }
+    }
+    fn visit_arm(&mut self, i: &'ast Arm) {
+        if self.eval_cfg(&i.attrs, i.span()) {
+            syn::visit::visit_arm(self, i);
+        }
+    }
+    fn visit_field_value(&mut self, i: &'ast FieldValue) {
+        if self.eval_cfg(&i.attrs, i.span()) {
+            syn::visit::visit_field_value(self, i);
+        }
+    }
+    fn visit_field_pat(&mut self, i: &'ast FieldPat) {
+        if self.eval_cfg(&i.attrs, i.span()) {
+            syn::visit::visit_field_pat(self, i);
+        }
+    }
+    fn visit_item_macro(&mut self, i: &'ast ItemMacro) {
+        let Some(branches) = parse_cfg_if(i) else {
+            return;
+        };
+        // Replace the whole invocation with the selected branch if the conditions can be decided.
+        let selected = select_cfg_if_branch(&branches, self.cfg);
+        match selected {
+            Some(branch) => {
+                let range = i.span().byte_range();
+                self.parts.push(Part::Text(self.offset..range.start));
+                if let Some(branch) = branch {
+                    self.offset = branch.brace.span.open().byte_range().end;
+                    for item in &branch.items {
+                        self.visit_item(item);
+                    }
+                    let end = branch.brace.span.close().byte_range().start;
+                    self.parts.push(Part::Text(self.offset..end));
+                }
+                self.offset = range.end;
+            }
+            None => {
+                for branch in &branches {
+                    for item in &branch.items {
+                        self.visit_item(item);
+                    }
+                }
+            }
+        }
     }
 }
@@ -1,21 +1,169 @@
-use std::{path::PathBuf, process::ExitCode};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process::{self, ExitCode},
+    sync::mpsc,
+    time::Duration,
+};
 
+use anyhow::anyhow;
 use clap::Parser;
-use expand_mod::{expand_from_path, ExpandError};
+use clipboard::ClipboardBackend;
+use expand_mod::{CfgSet, ExpandError, Expander, FeatureSelection, FormatStyle, Formatter};
+use notify::{Event, RecursiveMode, Watcher};
+
+mod clipboard;
 
 /// Expand `mod module_name;` in `.rs` files and combine the module tree consisting of multiple files into a single file.
 #[derive(clap::Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Copy the result to the clipboard instead of stdout.
     #[clap(long)]
     clipboard: bool,
 
+    /// The method used to copy the result to the clipboard.
+    #[clap(long, value_enum, default_value_t)]
+    clipboard_backend: ClipboardBackend,
+
+    /// The command that receives the result from stdin with `--clipboard-backend command` (e.g. `xclip -selection clipboard`).
+    #[clap(long)]
+    clipboard_command: Option<String>,
+
+    /// Write the result to the file instead of stdout.
+    #[clap(short, long, conflicts_with = "clipboard")]
+    output: Option<PathBuf>,
+
+    /// Format the result with the given tool.
+    #[clap(long, value_enum)]
+    format: Option<FormatStyle>,
+
+    /// Format only the inlined modules and leave the rest of the root file untouched.
+    #[clap(long, requires = "format")]
+    format_inlined_only: bool,
+
+    /// Indent the inlined modules by the given number of spaces, or `tab`, per nesting level.
+    #[clap(long, value_parser = parse_indent)]
+    indent: Option<String>,
+
+    /// Enable a configuration option such as `unix` or `feature="std"`, like `--cfg` of rustc.
+    ///
+    /// When specified, items, fields, match arms, statements and expressions whose `#[cfg]` is false are removed,
+    /// and so are modules whose files start with a false `#![cfg]`. `#[cfg_attr]` is replaced by its attributes if
+    /// the predicate is true, and `cfg_if!` invocations whose conditions can be decided are replaced by the selected
+    /// branch. Predicates on other keys are kept with a warning.
+    #[clap(long = "cfg", value_name = "SPEC")]
+    cfgs: Vec<String>,
+
+    /// Enable the features of the crate, which are used to evaluate `cfg(feature = "...")`.
+    #[clap(short = 'F', long, value_name = "FEATURES", value_delimiter = ',')]
+    features: Vec<String>,
+
+    /// Enable all features of the crate.
+    #[clap(long)]
+    all_features: bool,
+
+    /// Do not enable the `default` feature of the crate.
+    #[clap(long)]
+    no_default_features: bool,
+
+    /// Merge the second and subsequent files into the first file as modules named after their file stems.
+    #[clap(long)]
+    merge: bool,
+
+    /// The path used for the source read from stdin with `-`.
+    ///
+    /// `mod` declarations in the source are resolved relative to this path.
+    #[clap(long, default_value = "<stdin>")]
+    stdin_path: PathBuf,
+
+    /// Bundle the dependency with the given name into the crate as a module.
+    ///
+    /// The source is looked up in the registry cache of Cargo, or in `--vendor-dir`, at the version in `Cargo.lock`.
+    /// Dependencies with build scripts and proc-macro crates cannot be bundled. Target-specific dependencies are
+    /// bundled only if their `cfg(...)` is true with `--cfg`.
+    #[clap(long, value_name = "NAME", value_delimiter = ',')]
+    bundle: Vec<String>,
+
+    /// The directory created by `cargo vendor` used to look up the sources of bundled dependencies.
+    #[clap(long, requires = "bundle")]
+    vendor_dir: Option<PathBuf>,
+
+    /// The edition of the crate, used instead of the one in `Cargo.toml`.
+    ///
+    /// Paths in 2015-edition crates, including bundled ones, are rewritten into the 2018 form.
+    #[clap(long, value_parser = ["2015", "2018", "2021", "2024"])]
+    edition: Option<String>,
+
+    /// Warn about references to external crates other than the given ones and the bundled ones.
+    #[clap(long, value_name = "NAMES", value_delimiter = ',', num_args = 1)]
+    allow_extern: Option<Vec<String>>,
+
+    /// Replace the bodies of functions with `{ unimplemented!() }`, leaving the signatures and doc comments.
+    ///
+    /// The bodies of `const fn` and functions returning `impl Trait` are kept.
+    #[clap(long)]
+    signatures_only: bool,
+
+    /// Also remove private functions, constants and statics that the kept code does not use.
+    ///
+    /// Private types and traits, and items with restricted visibility such as `pub(crate)`, are kept.
+    #[clap(long, requires = "signatures_only")]
+    public_only: bool,
+
+    /// Expand again whenever a source file changes.
+    #[clap(long)]
+    watch: bool,
+
+    /// The root files of the crates. `-` reads the source from stdin.
     files: Vec<PathBuf>,
 }
 
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Print the module tree of a crate.
+    Tree {
+        /// Print the tree as JSON.
+        #[clap(long)]
+        json: bool,
+
+        /// The root file of the crate.
+        file: PathBuf,
+    },
+    /// Print the dependencies between the modules of a crate, collected from `use` declarations and paths.
+    Deps {
+        /// The output format.
+        #[clap(long, value_enum, default_value_t)]
+        format: GraphFormat,
+
+        /// The root file of the crate.
+        file: PathBuf,
+    },
+    /// Print the `.rs` files that are not part of the crates, and exit with failure if there are any.
+    Orphans {
+        /// The root files of the crates. Files are searched in the directory of the first one.
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+enum GraphFormat {
+    /// Graphviz DOT language.
+    #[default]
+    Dot,
+    /// Mermaid flowchart.
+    Mermaid,
+}
+
 fn main() -> ExitCode {
     match run() {
-        Ok(_) => ExitCode::SUCCESS,
+        Ok(code) => code,
         Err(e) => {
             e.show();
             ExitCode::FAILURE
@@ -23,17 +171,238 @@ fn main() -> ExitCode {
     }
 }
 
-fn run() -> Result<(), ExpandError> {
-    let args = Args::parse();
+fn run() -> Result<ExitCode, ExpandError> {
+    let mut args = Args::parse();
+    if let Some(command) = &args.command {
+        return run_command(command);
+    }
+    let mut expander = Expander::new();
+    if args.files.iter().any(|f| f.as_os_str() == "-") {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .map_err(|e| anyhow!("Could not read stdin ({e})"))?;
+        expander.add_virtual_file(&args.stdin_path, text)?;
+        for file in &mut args.files {
+            if file.as_os_str() == "-" {
+                file.clone_from(&args.stdin_path);
+            }
+        }
+    }
+    let mut formatter = None;
+    if let (Some(style), Some(file)) = (args.format, args.files.first()) {
+        formatter = Some(Formatter::new(style, file)?);
+    }
+    expander.set_indent(args.indent.clone());
+    if !args.cfgs.is_empty() {
+        let mut cfg = CfgSet::new();
+        for spec in &args.cfgs {
+            cfg.insert_str(spec)?;
+        }
+        expander.set_cfg(Some(cfg));
+    }
+    expander.set_bundle(args.bundle.clone(), args.vendor_dir.clone());
+    expander.set_allowed_externs(args.allow_extern.clone());
+    expander.set_edition(args.edition.clone());
+    expander.set_signatures_only(args.signatures_only, args.public_only);
+    if !args.features.is_empty() || args.all_features || args.no_default_features {
+        expander.set_features(Some(FeatureSelection {
+            features: args.features.clone(),
+            all_features: args.all_features,
+            no_default_features: args.no_default_features,
+        }));
+    }
+    if args.format_inlined_only {
+        expander.set_inlined_formatter(formatter.take());
+    }
+    if args.watch {
+        watch(&args, expander, formatter.as_ref())?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let text = expand(&args, &mut expander, formatter.as_ref())?;
+    write(&args, text)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_command(command: &Command) -> Result<ExitCode, ExpandError> {
+    match command {
+        Command::Tree { json, file } => {
+            let tree = Expander::new().module_tree(file)?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&tree)?);
+            } else {
+                print!("{}", tree.to_text());
+            }
+        }
+        Command::Deps { format, file } => {
+            let graph = Expander::new().module_graph(file)?;
+            match format {
+                GraphFormat::Dot => print!("{}", graph.to_dot()),
+                GraphFormat::Mermaid => print!("{}", graph.to_mermaid()),
+            }
+        }
+        Command::Orphans { files } => {
+            let orphans = Expander::new().orphans(files)?;
+            for orphan in &orphans {
+                println!("{}", orphan.display());
+            }
+            if !orphans.is_empty() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn parse_indent(s: &str) -> Result<String, String> {
+    if s == "tab" {
+        return Ok("\t".to_string());
+    }
+    match s.parse::<usize>() {
+        Ok(n) => Ok(" ".repeat(n)),
+        Err(_) => Err("expected a number of spaces or `tab`".to_string()),
+    }
+}
+
+fn expand(
+    args: &Args,
+    expander: &mut Expander,
+    formatter: Option<&Formatter>,
+) -> Result<String, ExpandError> {
     let mut text = String::new();
-    for file in &args.files {
-        let root = file.canonicalize()?.parent().unwrap().to_path_buf();
-        text.push_str(&expand_from_path(&root, file, true)?);
+    if args.merge {
+        text = expander.expand_merged(&args.files)?;
+    } else {
+        for file in &args.files {
+            text.push_str(&expander.expand(file)?);
+        }
+    }
+    for w in expander.take_warnings() {
+        w.show();
     }
+    if let Some(formatter) = formatter {
+        text = formatter.format(&text)?;
+    }
+    Ok(text)
+}
+
+fn write(args: &Args, text: String) -> Result<(), ExpandError> {
     if args.clipboard {
-        arboard::Clipboard::new()?.set_text(text)?;
+        clipboard::set_text(
+            args.clipboard_backend,
+            args.clipboard_command.as_deref(),
+            &text,
+        )?;
+    } else if let Some(output) = &args.output {
+        write_output(output, &text)
+            .map_err(|e| anyhow!("Could not write file : `{}` ({e})", output.display()))?;
     } else {
-        println!("{text}");
+        print!("{text}");
+    }
+    Ok(())
+}
+
+/// Writes `text` to `path` by renaming a temporary file, so that readers never see a partially written file.
+///
+/// The file is left untouched if it already has the same content.
+fn write_output(path: &Path, text: &str) -> io::Result<()> {
+    if fs::read(path).is_ok_and(|old| old == text.as_bytes()) {
+        return Ok(());
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::other("output path has no file name"))?;
+    let temp = dir.join(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        process::id()
+    ));
+    fs::write(&temp, text)?;
+    if let Err(e) = fs::rename(&temp, path) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
     }
     Ok(())
 }
+
+/// Expands the files and writes the result again each time a source file in the watched directories changes.
+///
+/// The directories containing the files read by the last expansion and their module directories are watched,
+/// so that newly added module files are also picked up.
+fn watch(
+    args: &Args,
+    mut expander: Expander,
+    formatter: Option<&Formatter>,
+) -> Result<(), ExpandError> {
+    if !args.clipboard && args.output.is_none() {
+        return Err(anyhow!("`--watch` requires `--output` or `--clipboard`").into());
+    }
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    let mut watched = HashSet::new();
+    loop {
+        let result = expand(args, &mut expander, formatter).and_then(|text| write(args, text));
+        let failed = result.is_err();
+        if let Err(e) = result {
+            e.show();
+        }
+        let mut dirs = HashSet::new();
+        let mut files = HashSet::new();
+        for file in args.files.iter().chain(&expander.take_files()) {
+            let Ok(file) = file.canonicalize() else {
+                continue;
+            };
+            files.insert(file.clone());
+            // The directory where `mod` declarations in the file are resolved.
+            let mod_dir = file.with_extension("");
+            if mod_dir.is_dir() {
+                dirs.insert(mod_dir);
+            }
+            if let Some(dir) = file.parent() {
+                dirs.insert(dir.to_path_buf());
+            }
+        }
+        for dir in watched.difference(&dirs) {
+            let _ = watcher.unwatch(dir);
+        }
+        for dir in dirs.difference(&watched) {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+        watched = dirs;
+
+        let output = args.output.as_ref().and_then(|p| p.canonicalize().ok());
+        loop {
+            let event = rx.recv()??;
+            if is_source_change(&event, &files, failed, output.as_deref()) {
+                break;
+            }
+        }
+        // Wait for the rest of the events caused by a single save.
+        while rx.recv_timeout(Duration::from_millis(100)).is_ok() {}
+    }
+}
+
+/// Returns whether `event` changes one of `files`, the files read by the last expansion.
+///
+/// If the expansion `failed`, any change other than to `output` counts, since it may create a missing file.
+fn is_source_change(
+    event: &Event,
+    files: &HashSet<PathBuf>,
+    failed: bool,
+    output: Option<&Path>,
+) -> bool {
+    !event.kind.is_access()
+        && event.paths.iter().any(|p| {
+            let p = p.canonicalize().unwrap_or_else(|_| p.clone());
+            if failed {
+                Some(p.as_path()) != output
+            } else {
+                files.contains(&p)
+            }
+        })
+}
@@ -0,0 +1,71 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use toml::{Table, Value};
+
+use crate::{with_path, Result};
+
+/// Returns the path of the nearest `Cargo.toml` in the directory of `path` or its ancestors.
+pub(crate) fn find_manifest(path: &Path) -> Option<PathBuf> {
+    let path = path.canonicalize().ok()?;
+    for dir in path.ancestors().skip(1) {
+        let manifest = dir.join("Cargo.toml");
+        if manifest.is_file() {
+            return Some(manifest);
+        }
+    }
+    None
+}
+
+pub(crate) fn read_manifest(path: &Path) -> Result<Table> {
+    let text = with_path(fs::read_to_string(path), path)?;
+    text.parse()
+        .map_err(|e| anyhow!("Could not parse `{}` ({e})", path.display()).into())
+}
+
+/// Returns the edition of the package whose manifest is `manifest` at `path`,
+/// or `None` if it cannot be determined.
+///
+/// `edition.workspace = true` is resolved with `[workspace.package]` of the workspace root.
+pub(crate) fn edition(manifest: &Table, path: &Path) -> Result<Option<String>> {
+    let Some(package) = manifest.get("package") else {
+        return Ok(None);
+    };
+    let edition = match package.get("edition") {
+        None => return Ok(Some("2015".to_string())),
+        Some(Value::String(edition)) => return Ok(Some(edition.clone())),
+        Some(edition) => edition,
+    };
+    if edition.get("workspace").and_then(|w| w.as_bool()) != Some(true) {
+        return Ok(None);
+    }
+    let Some(root) = find_workspace_root(package, path) else {
+        return Ok(None);
+    };
+    let root = read_manifest(&root)?;
+    Ok(root
+        .get("workspace")
+        .and_then(|w| w.get("package"))
+        .and_then(|p| p.get("edition"))
+        .and_then(|e| e.as_str())
+        .map(str::to_string))
+}
+
+/// Returns the path of the manifest of the workspace that the package at `path` belongs to.
+fn find_workspace_root(package: &Value, path: &Path) -> Option<PathBuf> {
+    let dir = path.parent()?;
+    if let Some(root) = package.get("workspace").and_then(|w| w.as_str()) {
+        return Some(dir.join(root).join("Cargo.toml"));
+    }
+    for dir in dir.ancestors() {
+        let manifest = dir.join("Cargo.toml");
+        if manifest.is_file() && read_manifest(&manifest).is_ok_and(|m| m.contains_key("workspace"))
+        {
+            return Some(manifest);
+        }
+    }
+    None
+}
@@ -0,0 +1,48 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{with_path, Expander, Result};
+
+impl Expander {
+    /// Returns the `.rs` files in the directory of `paths[0]` and its subdirectories
+    /// that are not read when expanding the crates whose root files are `paths`.
+    pub fn orphans(&mut self, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        let Some(first) = paths.first() else {
+            return Ok(Vec::new());
+        };
+        self.take_files();
+        for path in paths {
+            self.expand(path)?;
+        }
+        self.take_warnings();
+        let used: HashSet<PathBuf> = self
+            .take_files()
+            .iter()
+            .filter_map(|p| p.canonicalize().ok())
+            .collect();
+        let dir = match first.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut orphans = Vec::new();
+        collect_rs_files(dir, &mut orphans)?;
+        orphans.retain(|p| !p.canonicalize().is_ok_and(|p| used.contains(&p)));
+        orphans.sort();
+        Ok(orphans)
+    }
+}
+
+fn collect_rs_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in with_path(fs::read_dir(dir), dir)? {
+        let path = with_path(entry, dir)?.path();
+        if path.is_dir() {
+            collect_rs_files(&path, files)?;
+        } else if path.extension().is_some_and(|e| e == "rs") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,432 @@
+use std::{collections::HashMap, ops::Range};
+
+use proc_macro2::{TokenStream, TokenTree};
+use syn::{
+    punctuated::Punctuated, spanned::Spanned, visit::Visit, Attribute, Expr, File, Ident, Item,
+    ItemMacro, ItemUse, Macro, Token, UseTree, VisRestricted,
+};
+
+use crate::cfg::parse_cfg_if;
+
+/// How paths are rewritten when crates are bundled as modules of a single crate.
+#[derive(Default)]
+pub(crate) struct Rewrite {
+    /// The path of the module that the crate being expanded becomes, such as `crate::foo`.
+    /// `None` for the root crate.
+    pub crate_path: Option<String>,
+    /// The bundled crates that the crate being expanded depends on, keyed by their names in code.
+    pub externs: HashMap<String, ExternCrate>,
+    /// The macros imported by `#[macro_use] extern crate`, which are invoked by their paths instead.
+    pub macro_use: Vec<String>,
+    /// Whether the crate is written in the 2015 edition, where `use` paths and paths starting with `::` are
+    /// relative to the crate root. They are rewritten to start with `crate::`.
+    pub edition_2015: bool,
+    /// The crates that 2015-edition paths relative to the crate root may start with.
+    pub root_externs: Vec<String>,
+}
+impl Rewrite {
+    pub fn is_empty(&self) -> bool {
+        self.crate_path.is_none()
+            && self.externs.is_empty()
+            && self.macro_use.is_empty()
+            && !self.edition_2015
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ExternCrate {
+    /// The path of the module that the crate becomes.
+    pub path: String,
+    /// The `#[macro_export]` macros of the crate, which are placed at the root of the bundle.
+    pub macros: Vec<String>,
+}
+
+/// A replacement of a byte range of the source.
+pub(crate) type Edit = (Range<usize>, String);
+
+/// Returns the edits that rewrite the paths in `file` according to `rewrite`, sorted by their positions.
+///
+/// `is_crate_root` is true if `file` is the root file of the expanded crate.
+pub(crate) fn rewrite_edits(file: &File, rewrite: &Rewrite, is_crate_root: bool) -> Vec<Edit> {
+    let mut v = PathRewriter {
+        rewrite,
+        edits: Vec::new(),
+    };
+    v.visit_file(file);
+    if is_crate_root {
+        // `use dep::mac;` importing a `#[macro_export]` macro would become `use crate::mac;`, which conflicts with the
+        // macro itself placed at the crate root. The macro is already in scope there, so the import is removed.
+        for item in &file.items {
+            if let Item::Use(i) = item {
+                if imports_exported_macro(&i.tree, rewrite) {
+                    v.edits.push((i.span().byte_range(), String::new()));
+                }
+            }
+        }
+    }
+    v.edits.sort_by_key(|(r, _)| r.start);
+    v.edits.dedup_by_key(|(r, _)| r.start);
+    v.edits
+}
+
+/// Appends `s[range]` to `text`, applying the edits within the range.
+///
+/// Edits within the range replaced by a preceding edit, such as those in a removed node, are ignored.
+pub(crate) fn push_with_edits(text: &mut String, s: &str, range: Range<usize>, edits: &[Edit]) {
+    let mut offset = range.start;
+    let i = edits.partition_point(|(r, _)| r.start < range.start);
+    for (r, replacement) in &edits[i..] {
+        if r.end > range.end {
+            break;
+        }
+        if r.start < offset {
+            continue;
+        }
+        text.push_str(&s[offset..r.start]);
+        text.push_str(replacement);
+        offset = r.end;
+    }
+    text.push_str(&s[offset..range.end]);
+}
+
+/// Returns the names of the `#[macro_export]` macros defined in `file`.
+pub(crate) fn exported_macros(file: &File) -> Vec<String> {
+    struct V(Vec<String>);
+    impl<'ast> Visit<'ast> for V {
+        fn visit_item_macro(&mut self, i: &'ast ItemMacro) {
+            if let Some(ident) = &i.ident {
+                if i.attrs.iter().any(|a| a.path().is_ident("macro_export")) {
+                    self.0.push(ident.to_string());
+                }
+            }
+        }
+    }
+    let mut v = V(Vec::new());
+    v.visit_file(file);
+    v.0
+}
+
+/// Returns whether `tree` is `dep::mac` importing a `#[macro_export]` macro of a bundled crate without renaming it.
+fn imports_exported_macro(tree: &UseTree, rewrite: &Rewrite) -> bool {
+    let UseTree::Path(p) = tree else {
+        return false;
+    };
+    let UseTree::Name(n) = &*p.tree else {
+        return false;
+    };
+    rewrite
+        .externs
+        .get(&p.ident.to_string())
+        .is_some_and(|ext| ext.macros.iter().any(|m| n.ident == m))
+}
+
+struct PathRewriter<'a> {
+    rewrite: &'a Rewrite,
+    edits: Vec<Edit>,
+}
+impl PathRewriter<'_> {
+    /// Rewrites a path starting with `crate` or the name of a bundled crate, followed by `next`.
+    ///
+    /// `is_macro` is true if `next` names a macro, which is placed at the root of the bundle by `#[macro_export]`.
+    fn rewrite_first(
+        &mut self,
+        leading_colon: Option<&Token![::]>,
+        first: &Ident,
+        next: Option<&Ident>,
+        is_macro: bool,
+    ) {
+        let start = match leading_colon {
+            Some(c) => c.spans[0].byte_range().start,
+            None => first.span().byte_range().start,
+        };
+        let end = first.span().byte_range().end;
+        if first == "crate" && leading_colon.is_none() {
+            if let Some(crate_path) = &self.rewrite.crate_path {
+                if !is_macro {
+                    self.edits.push((start..end, crate_path.clone()));
+                }
+            }
+            return;
+        }
+        let Some(ext) = self.rewrite.externs.get(&first.to_string()) else {
+            return;
+        };
+        match next {
+            Some(next) if is_macro || ext.macros.iter().any(|m| next == m) => {
+                let next_start = next.span().byte_range().start;
+                self.edits.push((start..next_start, "crate::".to_string()));
+            }
+            _ => self.edits.push((start..end, ext.path.clone())),
+        }
+    }
+    /// Rewrites a 2015-edition path relative to the crate root, whose first segment is `first`, to start with
+    /// `crate::`.
+    fn rewrite_2015(&mut self, leading_colon: Option<&Token![::]>, first: &Ident) {
+        if !self.rewrite.edition_2015 {
+            return;
+        }
+        let name = first.to_string();
+        if ["crate", "self", "super", "Self"].contains(&name.as_str())
+            || self.rewrite.root_externs.contains(&name)
+            || self.rewrite.externs.contains_key(&name)
+        {
+            return;
+        }
+        let crate_path = self.rewrite.crate_path.as_deref().unwrap_or("crate");
+        let range = match leading_colon {
+            Some(c) => c.spans[0].byte_range().start..c.spans[1].byte_range().end,
+            None => {
+                let start = first.span().byte_range().start;
+                start..start
+            }
+        };
+        self.edits.push((range, format!("{crate_path}::")));
+    }
+    /// Rewrites the paths in a `use` tree starting at its root.
+    fn rewrite_use_tree(&mut self, leading_colon: Option<&Token![::]>, tree: &UseTree) {
+        let (first, next) = match tree {
+            UseTree::Path(p) => match &*p.tree {
+                UseTree::Name(n) => (&p.ident, Some(&n.ident)),
+                UseTree::Rename(r) => (&p.ident, Some(&r.ident)),
+                _ => (&p.ident, None),
+            },
+            UseTree::Name(n) => (&n.ident, None),
+            UseTree::Rename(r) => (&r.ident, None),
+            UseTree::Group(g) if leading_colon.is_none() => {
+                for tree in &g.items {
+                    self.rewrite_use_tree(None, tree);
+                }
+                return;
+            }
+            _ => return,
+        };
+        self.rewrite_2015(leading_colon, first);
+        self.rewrite_first(leading_colon, first, next, false);
+    }
+    /// Rewrites the paths starting with `$crate`, `crate` or the name of a bundled crate in the body of `macro_rules!`.
+    fn rewrite_macro_rules(&mut self, tokens: TokenStream) {
+        let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+        let is_punct = |j: usize, c: char| matches!(tokens.get(j), Some(TokenTree::Punct(p)) if p.as_char() == c);
+        for (i, tt) in tokens.iter().enumerate() {
+            let ident = match tt {
+                TokenTree::Group(g) => {
+                    self.rewrite_macro_rules(g.stream());
+                    continue;
+                }
+                TokenTree::Ident(ident) => ident,
+                _ => continue,
+            };
+            let after_colon = i >= 2 && is_punct(i - 1, ':') && is_punct(i - 2, ':');
+            let after_dollar = i > 0 && is_punct(i - 1, '$');
+            if is_punct(i + 1, '!')
+                && !after_colon
+                && !after_dollar
+                && self.rewrite.macro_use.iter().any(|n| ident == n)
+            {
+                let start = ident.span().byte_range().start;
+                self.edits.push((start..start, "crate::".to_string()));
+            }
+            if !is_punct(i + 1, ':') || !is_punct(i + 2, ':') {
+                continue;
+            }
+            let next = match tokens.get(i + 3) {
+                Some(TokenTree::Ident(next)) => Some(next),
+                _ => None,
+            };
+            let is_macro = next.is_some() && is_punct(i + 4, '!');
+            if ident == "crate" {
+                if let Some(crate_path) = &self.rewrite.crate_path {
+                    if !is_macro {
+                        // `$crate` is rewritten to `$crate::name`.
+                        self.edits
+                            .push((ident.span().byte_range(), crate_path.clone()));
+                    }
+                }
+            } else if let Some(ext) = self.rewrite.externs.get(&ident.to_string()) {
+                if after_colon || after_dollar {
+                    continue;
+                }
+                match next {
+                    Some(next) if is_macro || ext.macros.iter().any(|m| next == m) => {
+                        let range = ident.span().byte_range().start..next.span().byte_range().start;
+                        self.edits.push((range, "crate::".to_string()));
+                    }
+                    _ => self
+                        .edits
+                        .push((ident.span().byte_range(), ext.path.clone())),
+                }
+            }
+        }
+    }
+}
+impl<'ast> Visit<'ast> for PathRewriter<'_> {
+    fn visit_path(&mut self, p: &'ast syn::Path) {
+        let mut segments = p.segments.iter();
+        if let Some(first) = segments.next() {
+            if p.leading_colon.is_some() {
+                self.rewrite_2015(p.leading_colon.as_ref(), &first.ident);
+            }
+            if p.leading_colon.is_some() || p.segments.len() > 1 || first.ident == "crate" {
+                let next = segments.next().map(|s| &s.ident);
+                self.rewrite_first(p.leading_colon.as_ref(), &first.ident, next, false);
+            }
+        }
+        syn::visit::visit_path(self, p);
+    }
+    fn visit_item_use(&mut self, i: &'ast ItemUse) {
+        self.rewrite_use_tree(i.leading_colon.as_ref(), &i.tree);
+    }
+    fn visit_vis_restricted(&mut self, i: &'ast VisRestricted) {
+        // `pub(crate)`, `pub(self)` and `pub(super)` keep their meanings.
+        if i.in_token.is_some() {
+            if let Some(first) = i.path.segments.first() {
+                if i.path.leading_colon.is_none() {
+                    self.rewrite_2015(None, &first.ident);
+                }
+            }
+            self.visit_path(&i.path);
+        }
+    }
+    fn visit_attribute(&mut self, _: &'ast Attribute) {}
+    fn visit_item_macro(&mut self, i: &'ast ItemMacro) {
+        if i.mac.path.is_ident("macro_rules") {
+            self.rewrite_macro_rules(i.mac.tokens.clone());
+        } else if let Some(branches) = parse_cfg_if(i) {
+            // The items in all branches are rewritten since the unselected ones are removed afterwards.
+            for branch in &branches {
+                for item in &branch.items {
+                    self.visit_item(item);
+                }
+            }
+        } else {
+            self.visit_macro(&i.mac);
+        }
+    }
+    fn visit_macro(&mut self, m: &'ast Macro) {
+        if m.path.leading_colon.is_none()
+            && self.rewrite.macro_use.iter().any(|n| m.path.is_ident(n))
+        {
+            let start = m.path.span().byte_range().start;
+            self.edits.push((start..start, "crate::".to_string()));
+        } else if let [first, next] = &m.path.segments.iter().collect::<Vec<_>>()[..] {
+            self.rewrite_first(
+                m.path.leading_colon.as_ref(),
+                &first.ident,
+                Some(&next.ident),
+                true,
+            );
+        } else {
+            self.visit_path(&m.path);
+        }
+        // Arguments of macros such as `println!` and `assert_eq!` are usually expressions.
+        if let Ok(args) = m.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated) {
+            for arg in &args {
+                self.visit_expr(arg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(s: &str, rewrite: &Rewrite, is_crate_root: bool) -> String {
+        let file = syn::parse_file(s).unwrap();
+        let edits = rewrite_edits(&file, rewrite, is_crate_root);
+        let mut text = String::new();
+        push_with_edits(&mut text, s, 0..s.len(), &edits);
+        text
+    }
+    fn bundled(crate_path: &str) -> Rewrite {
+        Rewrite {
+            crate_path: Some(crate_path.to_string()),
+            ..Rewrite::default()
+        }
+    }
+    fn with_dep() -> Rewrite {
+        let dep = ExternCrate {
+            path: "crate::dep".to_string(),
+            macros: vec!["mac".to_string()],
+        };
+        Rewrite {
+            externs: HashMap::from([("dep".to_string(), dep)]),
+            ..Rewrite::default()
+        }
+    }
+
+    #[test]
+    fn crate_paths_of_bundled_crate() {
+        let s = "use crate::a::b;\npub(crate) fn f() -> crate::T { crate::g() }\n";
+        assert_eq!(
+            rewrite(s, &bundled("crate::liba"), false),
+            "use crate::liba::a::b;\npub(crate) fn f() -> crate::liba::T { crate::liba::g() }\n"
+        );
+    }
+
+    #[test]
+    fn dollar_crate_in_macro_rules() {
+        let s = "macro_rules! m { () => { $crate::f(); $crate::m!() } }\n";
+        assert_eq!(
+            rewrite(s, &bundled("crate::liba"), false),
+            "macro_rules! m { () => { $crate::liba::f(); $crate::m!() } }\n"
+        );
+    }
+
+    #[test]
+    fn paths_of_bundled_deps() {
+        let s = "use dep::Foo;\nuse {dep::Bar, std::fmt};\nfn f() { dep::g(); dep::mac!(); ::dep::h(); }\n";
+        assert_eq!(
+            rewrite(s, &with_dep(), false),
+            "use crate::dep::Foo;\nuse {crate::dep::Bar, std::fmt};\nfn f() { crate::dep::g(); crate::mac!(); crate::dep::h(); }\n"
+        );
+    }
+
+    #[test]
+    fn imports_of_exported_macros_at_crate_root() {
+        let s = "use dep::mac;\nuse dep::Foo;\n";
+        assert_eq!(rewrite(s, &with_dep(), true), "\nuse crate::dep::Foo;\n");
+        assert_eq!(
+            rewrite(s, &with_dep(), false),
+            "use crate::mac;\nuse crate::dep::Foo;\n"
+        );
+    }
+
+    #[test]
+    fn macro_use() {
+        let r = Rewrite {
+            macro_use: vec!["mac".to_string()],
+            ..Rewrite::default()
+        };
+        let s = "fn f() { mac!(); other!(); }\n";
+        assert_eq!(
+            rewrite(s, &r, false),
+            "fn f() { crate::mac!(); other!(); }\n"
+        );
+    }
+
+    #[test]
+    fn edition_2015_paths() {
+        let r = Rewrite {
+            edition_2015: true,
+            root_externs: vec!["std".to_string(), "core".to_string()],
+            ..Rewrite::default()
+        };
+        let s = "use a::b;\nuse std::fmt;\nuse self::c;\nfn f() { ::a::g(); a::h(); }\n";
+        assert_eq!(
+            rewrite(s, &r, false),
+            "use crate::a::b;\nuse std::fmt;\nuse self::c;\nfn f() { crate::a::g(); a::h(); }\n"
+        );
+    }
+
+    #[test]
+    fn cfg_if_branches() {
+        let s = "cfg_if! {\n    if #[cfg(unix)] {\n        use crate::a;\n    } else {\n        use dep::b;\n    }\n}\n";
+        let mut r = with_dep();
+        r.crate_path = Some("crate::liba".to_string());
+        assert_eq!(
+            rewrite(s, &r, false),
+            "cfg_if! {\n    if #[cfg(unix)] {\n        use crate::liba::a;\n    } else {\n        use crate::dep::b;\n    }\n}\n"
+        );
+    }
+}
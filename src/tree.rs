@@ -0,0 +1,205 @@
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use syn::{spanned::Spanned, Attribute, Item, ItemMod, Visibility};
+
+use crate::{
+    absolute_path, cfg::expand_cfg_if_with_conditions, parse_file, path_from_attrs, path_from_mod,
+    shebang_end, with_source, Expander, FileKind, Result,
+};
+
+/// A module in the module tree of a crate.
+#[derive(Debug, Serialize)]
+pub struct Module {
+    /// The name of the module, or `crate` for the crate root.
+    pub name: String,
+    /// The file containing the module's items.
+    pub path: PathBuf,
+    pub resolution: Resolution,
+    /// The visibility such as `pub` or `pub(crate)`. Empty for private modules.
+    pub visibility: String,
+    /// The `#[cfg(...)]` attributes of the module, preceded by the conditions of the `cfg_if!` branches containing it
+    /// such as `#[cfg(not(unix))]`.
+    pub cfg: Vec<String>,
+    pub children: Vec<Module>,
+}
+
+/// How the source of a module was determined.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    /// The crate root.
+    Root,
+    /// `name.rs`
+    File,
+    /// `name/mod.rs`
+    ModRs,
+    /// `#[path = "..."]`
+    PathAttr,
+    /// `mod name { ... }`
+    Inline,
+}
+impl Resolution {
+    fn as_str(self) -> &'static str {
+        match self {
+            Resolution::Root => "root",
+            Resolution::File => "name.rs",
+            Resolution::ModRs => "name/mod.rs",
+            Resolution::PathAttr => "#[path]",
+            Resolution::Inline => "inline",
+        }
+    }
+}
+
+impl Module {
+    /// Renders the tree as indented text, one module per line.
+    pub fn to_text(&self) -> String {
+        let mut s = String::new();
+        self.write_text(&mut s, "", "");
+        s
+    }
+    fn write_text(&self, s: &mut String, head: &str, tail: &str) {
+        s.push_str(head);
+        if !self.visibility.is_empty() {
+            s.push_str(&self.visibility);
+            s.push(' ');
+        }
+        s.push_str(&self.name);
+        s.push_str(&format!(
+            " ({}) [{}]",
+            self.path.display(),
+            self.resolution.as_str()
+        ));
+        for cfg in &self.cfg {
+            s.push(' ');
+            s.push_str(cfg);
+        }
+        s.push('\n');
+        for (i, child) in self.children.iter().enumerate() {
+            let (head, next) = if i + 1 == self.children.len() {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            child.write_text(s, &format!("{tail}{head}"), &format!("{tail}{next}"));
+        }
+    }
+}
+
+impl Expander {
+    /// Returns the module tree of the crate whose root file is `path`.
+    ///
+    /// Modules are resolved in the same way as [`Expander::expand`],
+    /// so inline modules are listed but `mod name;` inside them is not followed.
+    pub fn module_tree(&mut self, path: &Path) -> Result<Module> {
+        let root = absolute_path(path)?.parent().unwrap().to_path_buf();
+        let children = self.module_tree_children(&root, path, FileKind::CrateRoot)?;
+        Ok(Module {
+            name: "crate".to_string(),
+            path: path.to_path_buf(),
+            resolution: Resolution::Root,
+            visibility: String::new(),
+            cfg: Vec::new(),
+            children,
+        })
+    }
+    fn module_tree_children(
+        &mut self,
+        root: &Path,
+        path: &Path,
+        kind: FileKind,
+    ) -> Result<Vec<Module>> {
+        let s = self.read_file(root, path)?;
+        let r = (|| {
+            let file = parse_file(&s, shebang_end(&s))?;
+            let mut children = Vec::new();
+            for (item, conditions) in expand_cfg_if_with_conditions(&file.items, self.cfg.as_ref())
+            {
+                let Item::Mod(m) = &item else {
+                    continue;
+                };
+                let cfg = conditions.iter().map(|c| c.to_attr_text(&s)).collect();
+                children.push(self.module_tree_node(root, path, kind, m, cfg, &s)?);
+            }
+            Ok(children)
+        })();
+        with_source(r, path, &s)
+    }
+    /// Returns the node of `m`, whose `cfg` starts with `cfg`, the conditions of the `cfg_if!` branches containing it.
+    fn module_tree_node(
+        &mut self,
+        root: &Path,
+        path: &Path,
+        kind: FileKind,
+        m: &ItemMod,
+        mut cfg: Vec<String>,
+        s: &str,
+    ) -> Result<Module> {
+        cfg.extend(
+            m.attrs
+                .iter()
+                .filter(|a| a.path().is_ident("cfg"))
+                .map(|a| attr_text(a, s)),
+        );
+        let mut node = Module {
+            name: m.ident.to_string(),
+            path: path.to_path_buf(),
+            resolution: Resolution::Inline,
+            visibility: visibility_text(&m.vis),
+            cfg,
+            children: Vec::new(),
+        };
+        if let Some((_, items)) = &m.content {
+            for (item, conditions) in expand_cfg_if_with_conditions(items, self.cfg.as_ref()) {
+                if let Item::Mod(m) = &item {
+                    if m.content.is_some() {
+                        let cfg = conditions.iter().map(|c| c.to_attr_text(s)).collect();
+                        node.children
+                            .push(self.module_tree_node(root, path, kind, m, cfg, s)?);
+                    }
+                }
+            }
+        } else {
+            let mod_path = path_from_mod(path, kind.owns_dir(), m, |p| self.is_file(p))?;
+            node.resolution = if path_from_attrs(&m.attrs).is_some() {
+                Resolution::PathAttr
+            } else if mod_path.file_name().is_some_and(|n| n == "mod.rs") {
+                Resolution::ModRs
+            } else {
+                Resolution::File
+            };
+            node.children = self
+                .module_tree_children(root, &mod_path, FileKind::of_mod(m))
+                .map_err(|e| e.included_from(path, m))?;
+            node.path = mod_path;
+        }
+        Ok(node)
+    }
+}
+
+fn attr_text(attr: &Attribute, s: &str) -> String {
+    s.get(attr.span().byte_range())
+        .map(|t| t.to_string())
+        .unwrap_or_default()
+}
+
+fn visibility_text(vis: &Visibility) -> String {
+    match vis {
+        Visibility::Public(_) => "pub".to_string(),
+        Visibility::Restricted(r) => {
+            let path = r
+                .path
+                .segments
+                .iter()
+                .map(|s| s.ident.to_string())
+                .collect::<Vec<_>>()
+                .join("::");
+            if r.in_token.is_some() {
+                format!("pub(in {path})")
+            } else {
+                format!("pub({path})")
+            }
+        }
+        Visibility::Inherited => String::new(),
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use expand_mod::{CfgSet, Expander};
+
+/// Creates a package `app` depending on the library `liba` by path, and returns the path of the root file of `app`.
+///
+/// `files` are the source files of `liba` relative to its `src` directory.
+fn app_with_liba(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("app/src")).unwrap();
+    fs::create_dir_all(root.join("liba/src")).unwrap();
+    fs::write(
+        root.join("app/Cargo.toml"),
+        "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\nliba = { path = \"../liba\" }\n",
+    )
+    .unwrap();
+    fs::write(
+        root.join("app/Cargo.lock"),
+        "version = 4\n\n[[package]]\nname = \"app\"\nversion = \"0.1.0\"\ndependencies = [\n \"liba\",\n]\n\n[[package]]\nname = \"liba\"\nversion = \"0.1.0\"\n",
+    )
+    .unwrap();
+    fs::write(
+        root.join("liba/Cargo.toml"),
+        "[package]\nname = \"liba\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+    )
+    .unwrap();
+    for (path, text) in files {
+        fs::write(root.join("liba/src").join(path), text).unwrap();
+    }
+    let path = root.join("app/src/main.rs");
+    fs::write(&path, "fn main() {\n    liba::f();\n}\n").unwrap();
+    path
+}
+
+fn expand_bundled(path: &Path, cfg: Option<CfgSet>) -> String {
+    let mut e = Expander::new();
+    e.set_bundle(vec!["liba".to_string()], None);
+    e.set_cfg(cfg);
+    e.expand(path).unwrap_or_else(|e| {
+        e.show();
+        panic!("failed to expand `{}`", path.display())
+    })
+}
+
+const CFG_IF_LIB: &str = "cfg_if::cfg_if! {
+    if #[cfg(unix)] {
+        pub use crate::imp::f;
+        mod imp;
+    } else {
+        pub use crate::fallback::f;
+        mod fallback {
+            pub fn f() {}
+        }
+    }
+}
+";
+
+#[test]
+fn cfg_if_branches_are_rewritten() {
+    let path = app_with_liba(
+        "cfg_if_branches_are_rewritten",
+        &[("lib.rs", CFG_IF_LIB), ("imp.rs", "pub fn f() {}\n")],
+    );
+    let text = expand_bundled(&path, None);
+    assert!(text.contains("pub use crate::liba::imp::f;"), "{text}");
+    assert!(text.contains("pub use crate::liba::fallback::f;"), "{text}");
+    assert!(!text.contains("crate::imp"), "{text}");
+}
+
+#[test]
+fn selected_cfg_if_branch_is_rewritten() {
+    let path = app_with_liba(
+        "selected_cfg_if_branch_is_rewritten",
+        &[("lib.rs", CFG_IF_LIB), ("imp.rs", "pub fn f() {}\n")],
+    );
+    let mut cfg = CfgSet::new();
+    cfg.insert("unix", None);
+    let text = expand_bundled(&path, Some(cfg));
+    assert!(text.contains("pub use crate::liba::imp::f;"), "{text}");
+    assert!(!text.contains("cfg_if!"), "{text}");
+    assert!(!text.contains("fallback"), "{text}");
+}
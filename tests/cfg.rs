@@ -0,0 +1,70 @@
+use std::{fs, path::PathBuf};
+
+use expand_mod::{CfgSet, Expander};
+
+/// Writes `files` into a directory and expands the crate whose root file is `main.rs` with `cfg`.
+fn expand_with_cfg(name: &str, files: &[(&str, &str)], cfg: &[&str]) -> String {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&root);
+    for (path, text) in files {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    }
+    let mut set = CfgSet::new();
+    for option in cfg {
+        set.insert_str(option).unwrap_or_else(|e| {
+            e.show();
+            panic!("invalid cfg option `{option}`")
+        });
+    }
+    let path = root.join("main.rs");
+    let mut e = Expander::new();
+    e.set_cfg(Some(set));
+    e.expand(&path).unwrap_or_else(|e| {
+        e.show();
+        panic!("failed to expand `{}`", path.display())
+    })
+}
+
+const MAIN: &str = "mod a;
+#[path = \"b/x.rs\"]
+pub mod b;
+fn main() {}
+";
+
+#[test]
+fn module_with_false_inner_cfg_is_removed() {
+    let files = [
+        ("main.rs", MAIN),
+        ("a.rs", "#![cfg(target_os = \"linux\")]\npub fn a() {}\n"),
+        (
+            "b/x.rs",
+            "#![cfg(target_os = \"windows\")]\npub fn b() {}\n",
+        ),
+    ];
+    let text = expand_with_cfg(
+        "module_with_false_inner_cfg_is_removed",
+        &files,
+        &["target_os=\"linux\""],
+    );
+    assert_eq!(text, "mod a {\npub fn a() {}\n}\n\nfn main() {}\n");
+}
+
+#[test]
+fn module_with_undecided_inner_cfg_is_kept() {
+    let files = [
+        ("main.rs", MAIN),
+        ("a.rs", "#![cfg(unix)]\npub fn a() {}\n"),
+        ("b/x.rs", "pub fn b() {}\n"),
+    ];
+    let text = expand_with_cfg(
+        "module_with_undecided_inner_cfg_is_kept",
+        &files,
+        &["target_os=\"linux\""],
+    );
+    assert_eq!(
+        text,
+        "mod a {\n#![cfg(unix)]\npub fn a() {}\n}\n\npub mod b {\npub fn b() {}\n}\n\nfn main() {}\n"
+    );
+}
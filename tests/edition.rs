@@ -0,0 +1,73 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use expand_mod::Expander;
+
+fn expand(path: &Path) -> String {
+    Expander::new().expand(path).unwrap_or_else(|e| {
+        e.show();
+        panic!("failed to expand `{}`", path.display())
+    })
+}
+
+/// Creates a workspace whose `[workspace.package]` has `edition`, with a member inheriting it,
+/// and returns the path of the root file of the member.
+fn workspace_member(name: &str, edition: &str, main: &str) -> PathBuf {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("app/src")).unwrap();
+    fs::write(
+        root.join("Cargo.toml"),
+        format!(
+            "[workspace]\nmembers = [\"app\"]\n\n[workspace.package]\nedition = \"{edition}\"\n"
+        ),
+    )
+    .unwrap();
+    fs::write(
+        root.join("app/Cargo.toml"),
+        "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition.workspace = true\n",
+    )
+    .unwrap();
+    let path = root.join("app/src/main.rs");
+    fs::write(&path, main).unwrap();
+    path
+}
+
+#[test]
+fn inherited_edition_2021_keeps_paths() {
+    let main = "use proconio::input;\n\nfn main() {\n    ::itertools::max([1]);\n}\n";
+    let path = workspace_member("inherited_edition_2021", "2021", main);
+    let text = expand(&path);
+    assert_eq!(text, main);
+}
+
+#[test]
+fn inherited_edition_2015_rewrites_paths() {
+    let main = "use foo::bar;\n\nmod foo {\n    pub fn bar() {}\n}\n\nfn main() {}\n";
+    let path = workspace_member("inherited_edition_2015", "2015", main);
+    let text = expand(&path);
+    assert!(text.starts_with("use crate::foo::bar;\n"), "{text}");
+}
+
+#[test]
+fn merged_2015_files_keep_std_paths() {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("merged_2015_files_keep_std_paths");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let main = root.join("main.rs");
+    let helper = root.join("helper.rs");
+    fs::write(&main, "mod util {\n    pub fn f() {}\n}\n\nfn main() {}\n").unwrap();
+    fs::write(&helper, "use std::fmt;\nuse util::f;\n").unwrap();
+    let mut e = Expander::new();
+    e.set_edition(Some("2015".to_string()));
+    let text = e.expand_merged(&[main, helper]).unwrap_or_else(|e| {
+        e.show();
+        panic!("failed to expand")
+    });
+    assert!(
+        text.ends_with("mod helper {\nuse std::fmt;\nuse crate::util::f;\n}\n"),
+        "{text}"
+    );
+}
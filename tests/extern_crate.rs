@@ -0,0 +1,52 @@
+use std::{fs, path::PathBuf};
+
+use expand_mod::{Expander, FeatureSelection};
+
+/// Creates a package with the feature `nightly` whose files are `files`, and returns the path of its root file.
+fn package(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(
+        root.join("Cargo.toml"),
+        "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[features]\nnightly = []\n",
+    )
+    .unwrap();
+    for (path, text) in files {
+        fs::write(root.join("src").join(path), text).unwrap();
+    }
+    root.join("src/main.rs")
+}
+
+fn expand_with_features(name: &str, features: &[&str]) -> String {
+    let main = "#![cfg_attr(feature = \"nightly\", feature(test))]\nmod a;\nfn main() {}\n";
+    let a = "#[macro_use]\nextern crate foo;\n";
+    let path = package(name, &[("main.rs", main), ("a.rs", a)]);
+    let mut e = Expander::new();
+    e.set_features(Some(FeatureSelection {
+        features: features.iter().map(|f| f.to_string()).collect(),
+        ..FeatureSelection::default()
+    }));
+    e.expand(&path).unwrap_or_else(|e| {
+        e.show();
+        panic!("failed to expand `{}`", path.display())
+    })
+}
+
+#[test]
+fn hoisted_after_evaluated_cfg_attr() {
+    let text = expand_with_features("hoisted_after_evaluated_cfg_attr", &["nightly"]);
+    assert_eq!(
+        text,
+        "#![feature(test)]\n#[macro_use]\nextern crate foo;\nmod a {\n\n}\n\nfn main() {}\n"
+    );
+}
+
+#[test]
+fn hoisted_after_removed_cfg_attr() {
+    let text = expand_with_features("hoisted_after_removed_cfg_attr", &[]);
+    assert_eq!(
+        text,
+        "#[macro_use]\nextern crate foo;\nmod a {\n\n}\n\nfn main() {}\n"
+    );
+}
@@ -0,0 +1,82 @@
+use std::{fs, path::PathBuf};
+
+use expand_mod::Expander;
+
+fn expand_public_only(name: &str, main: &str) -> String {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let path = root.join("main.rs");
+    fs::write(&path, main).unwrap();
+    let mut e = Expander::new();
+    e.set_signatures_only(true, true);
+    e.expand(&path).unwrap_or_else(|e| {
+        e.show();
+        panic!("failed to expand `{}`", path.display())
+    })
+}
+
+#[test]
+fn public_only_keeps_private_types() {
+    let main = "struct Priv;\ntrait Helper {}\ntype Alias = Priv;\n\npub fn uses() -> Alias {\n    Priv\n}\n";
+    let text = expand_public_only("public_only_keeps_private_types", main);
+    assert_eq!(
+        text,
+        "struct Priv;\ntrait Helper {}\ntype Alias = Priv;\n\npub fn uses() -> Alias { unimplemented!() }\n"
+    );
+}
+
+#[test]
+fn public_only_keeps_used_private_values() {
+    let main = "const SIZE: usize = 4;
+const UNUSED: usize = 1;
+static COUNTER: u32 = 0;
+
+pub struct Buf {
+    data: [u8; SIZE],
+}
+
+fn helper() {}
+
+const fn twice(x: usize) -> usize {
+    x * 2
+}
+
+pub const fn size() -> usize {
+    twice(SIZE)
+}
+
+impl Buf {
+    const N: usize = 3;
+    const M: usize = 3;
+    pub fn arr(&self) -> [u8; Self::N] {
+        self.inner()
+    }
+    fn inner(&self) -> [u8; 3] {
+        [0; 3]
+    }
+}
+";
+    let text = expand_public_only("public_only_keeps_used_private_values", main);
+    assert_eq!(
+        text,
+        "const SIZE: usize = 4;
+pub struct Buf {
+    data: [u8; SIZE],
+}
+
+const fn twice(x: usize) -> usize {
+    x * 2
+}
+
+pub const fn size() -> usize {
+    twice(SIZE)
+}
+
+impl Buf {
+    const N: usize = 3;
+    pub fn arr(&self) -> [u8; Self::N] { unimplemented!() }
+}
+"
+    );
+}
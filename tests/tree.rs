@@ -0,0 +1,60 @@
+use std::{fs, path::PathBuf};
+
+use expand_mod::{Expander, Module};
+
+/// Writes `files` into a directory and returns the module tree of the crate whose root file is `main.rs`.
+fn module_tree(name: &str, files: &[(&str, &str)]) -> Module {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    for (path, text) in files {
+        fs::write(root.join(path), text).unwrap();
+    }
+    let path = root.join("main.rs");
+    Expander::new().module_tree(&path).unwrap_or_else(|e| {
+        e.show();
+        panic!("failed to read `{}`", path.display())
+    })
+}
+
+#[test]
+fn cfg_if_conditions() {
+    let main = "cfg_if::cfg_if! {
+    if #[cfg(unix)] {
+        mod unix;
+    } else if #[cfg(target_os = \"wasi\")] {
+        #[cfg(feature = \"x\")]
+        mod wasi {}
+    } else {
+        mod other;
+    }
+}
+";
+    let tree = module_tree(
+        "cfg_if_conditions",
+        &[("main.rs", main), ("unix.rs", ""), ("other.rs", "")],
+    );
+    let cfgs: Vec<(&str, Vec<&str>)> = tree
+        .children
+        .iter()
+        .map(|m| (&m.name[..], m.cfg.iter().map(|c| &c[..]).collect()))
+        .collect();
+    assert_eq!(
+        cfgs,
+        [
+            ("unix", vec!["#[cfg(unix)]"]),
+            (
+                "wasi",
+                vec![
+                    "#[cfg(not(unix))]",
+                    "#[cfg(target_os = \"wasi\")]",
+                    "#[cfg(feature = \"x\")]"
+                ]
+            ),
+            (
+                "other",
+                vec!["#[cfg(not(unix))]", "#[cfg(not(target_os = \"wasi\"))]"]
+            ),
+        ]
+    );
+}